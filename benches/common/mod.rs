@@ -0,0 +1,39 @@
+//! Helpers shared by the benchmarks, which run the real server binary.
+
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// A running server process, killed when dropped.
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the server binary on `addr` serving `serve_dir`, and wait until it listens.
+pub fn start_server(addr: SocketAddr, serve_dir: &Path, args: &[&str]) -> Server {
+    let server = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg(addr.to_string())
+        .arg(serve_dir)
+        .args(["--log-level", "off"])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("cannot start server");
+    let server = Server(server);
+
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("server did not start listening on {}", addr);
+}
@@ -0,0 +1,91 @@
+//! Compare the thread pool server with the async server under many slow clients.
+//!
+//! Every client downloads a file at a limited rate, as a client on a slow link would.
+//! The file is larger than what the socket buffers hold, so the server has to wait on
+//! the client for most of the transfer. The thread pool server can only wait on
+//! `--thread` clients at a time, the async server waits on all of them.
+//!
+//! Run with `cargo bench --features async --bench concurrency`.
+
+mod common;
+
+use common::start_server;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENTS: usize = 64;
+const THREADS: usize = 8;
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+/// Clients read this many bytes, then pause for `READ_PAUSE`.
+const READ_CHUNK: usize = 64 * 1024;
+const READ_PAUSE: Duration = Duration::from_millis(4);
+
+fn slow_client(addr: SocketAddr) -> usize {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /payload.bin\n").unwrap();
+
+    let mut buffer = vec![0; READ_CHUNK];
+    let mut received = 0;
+    loop {
+        match stream.read(&mut buffer).unwrap() {
+            0 => return received,
+            n => received += n,
+        }
+        thread::sleep(READ_PAUSE);
+    }
+}
+
+fn run(name: &str, addr: SocketAddr, serve_dir: &Path, is_async: bool) {
+    let threads = THREADS.to_string();
+    let mut args = vec!["--thread", &threads];
+    if is_async {
+        args.push("--async");
+    }
+    let _server = start_server(addr, serve_dir, &args);
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| thread::spawn(move || slow_client(addr)))
+        .collect();
+    let received: usize = clients.into_iter().map(|c| c.join().unwrap()).sum();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:<12} {} clients in {:>8.3}s, {:>8.1} requests/s, {} bytes received",
+        name,
+        CLIENTS,
+        elapsed.as_secs_f64(),
+        CLIENTS as f64 / elapsed.as_secs_f64(),
+        received
+    );
+}
+
+fn main() {
+    let serve_dir = std::env::temp_dir().join(format!("ssftp-bench-{}", std::process::id()));
+    fs::create_dir_all(&serve_dir).unwrap();
+    fs::write(serve_dir.join("payload.bin"), vec![0x5a; FILE_SIZE]).unwrap();
+
+    println!(
+        "{} clients downloading {} bytes at most {} bytes per {:?}, {} threads",
+        CLIENTS, FILE_SIZE, READ_CHUNK, READ_PAUSE, THREADS
+    );
+    run(
+        "thread pool",
+        "127.0.0.1:47301".parse().unwrap(),
+        &serve_dir,
+        false,
+    );
+    run(
+        "async",
+        "127.0.0.1:47302".parse().unwrap(),
+        &serve_dir,
+        true,
+    );
+
+    fs::remove_dir_all(&serve_dir).unwrap();
+}
@@ -0,0 +1,88 @@
+//! Compare serving GET requests with sendfile(2) against copying through user space.
+//!
+//! Clients download a large file from the server binary started with and without
+//! `--no-sendfile`. Besides the throughput, the CPU time used by the server process is
+//! read from `/proc`, which is where the copy through user space shows up.
+//!
+//! Run with `cargo bench --bench throughput`.
+
+mod common;
+
+use common::start_server;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+const FILE_SIZE: u64 = 1 << 30;
+const CLIENTS: usize = 4;
+const ROUNDS: usize = 4;
+
+/// User plus system CPU time of a process, in seconds.
+#[cfg(target_os = "linux")]
+fn cpu_seconds(pid: u32) -> f64 {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+    // the fields after the command name, which is in parentheses
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    ticks as f64 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_seconds(_pid: u32) -> f64 {
+    f64::NAN
+}
+
+fn download(addr: SocketAddr) -> u64 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /large.bin\n").unwrap();
+    io::copy(&mut stream, &mut io::sink()).unwrap()
+}
+
+fn run(name: &str, addr: SocketAddr, serve_dir: &Path, args: &[&str]) {
+    let server = start_server(addr, serve_dir, args);
+    let cpu_before = cpu_seconds(server.0.id());
+
+    let start = Instant::now();
+    let mut received = 0;
+    for _ in 0..ROUNDS {
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|_| thread::spawn(move || download(addr)))
+            .collect();
+        received += clients.into_iter().map(|c| c.join().unwrap()).sum::<u64>();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let cpu = cpu_seconds(server.0.id()) - cpu_before;
+
+    println!(
+        "{:<10} {:>8.1} MiB/s, server cpu {:>6.2}s ({:.3}s per GiB)",
+        name,
+        received as f64 / elapsed / (1 << 20) as f64,
+        cpu,
+        cpu / (received as f64 / (1 << 30) as f64)
+    );
+}
+
+fn main() {
+    let serve_dir = std::env::temp_dir().join(format!("ssftp-bench-{}", std::process::id()));
+    fs::create_dir_all(&serve_dir).unwrap();
+    // a sparse file, reading it costs no disk I/O
+    File::create(serve_dir.join("large.bin"))
+        .unwrap()
+        .set_len(FILE_SIZE)
+        .unwrap();
+
+    println!(
+        "{} rounds of {} clients downloading {} bytes",
+        ROUNDS, CLIENTS, FILE_SIZE
+    );
+    let addr = "127.0.0.1:47311".parse().unwrap();
+    run("sendfile", addr, &serve_dir, &[]);
+    let addr = "127.0.0.1:47312".parse().unwrap();
+    run("buffered", addr, &serve_dir, &["--no-sendfile"]);
+
+    fs::remove_dir_all(&serve_dir).unwrap();
+}
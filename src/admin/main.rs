@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::process;
+
+// cli arguments
+use clap::{App, AppSettings, Arg};
+
+/// Send one command to the admin socket and return the reply.
+#[cfg(unix)]
+fn send_command(socket: &str, command: &str) -> Result<String, Box<dyn Error>> {
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// The admin socket is a Unix socket, servers on other platforms have none.
+#[cfg(not(unix))]
+fn send_command(_socket: &str, _command: &str) -> Result<String, Box<dyn Error>> {
+    Err("the admin socket is only supported on Unix".into())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let app = App::new("admin")
+        .about("Inspect and control a running SSFTP server through its admin socket")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("socket")
+                .help("path of the admin socket of the server")
+                .value_name("path")
+                .required(true)
+                .index(1),
+        )
+        .subcommand(App::new("list").about("List active connections"))
+        .subcommand(
+            App::new("kill").about("Close a connection").arg(
+                Arg::with_name("id")
+                    .help("connection id, as shown by list")
+                    .required(true)
+                    .index(1),
+            ),
+        )
+        .subcommand(
+            App::new("log-level")
+                .about("Show or change the log level of the server")
+                .arg(
+                    Arg::with_name("level")
+                        .help("new log level")
+                        .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                        .index(1),
+                ),
+        )
+        .subcommand(App::new("reload").about("Reload the configuration of the server"));
+
+    let matches = app.get_matches();
+    let socket = matches.value_of("socket").unwrap();
+    let command = match matches.subcommand() {
+        ("kill", Some(sub)) => format!("kill {}", sub.value_of("id").unwrap()),
+        ("log-level", Some(sub)) => match sub.value_of("level") {
+            Some(level) => format!("log-level {}", level),
+            None => "log-level".into(),
+        },
+        (name, _) => name.into(),
+    };
+
+    let reply = send_command(socket, &command)?;
+    let mut lines = reply.splitn(2, '\n');
+    let status = lines.next().unwrap_or_default();
+    let body = lines.next().unwrap_or_default();
+    match status {
+        "OK" => print!("{}", body),
+        _ => {
+            eprint!("{}", body);
+            process::exit(1);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Async counterpart of [`SSFTPClient`](crate::client::SSFTPClient) on tokio, enabled
+//! by the `async` feature. Payloads are returned as `AsyncRead` streams, they are never
+//! compressed nor chunked since this client does not offer any encoding nor chunked
+//! framing.
+
+use crate::client::{
+    content_encoding, is_chunked, needs_negotiation, ByteCount, Capabilities, DirEntry,
+    DirResponse, GetResponse, InfoResponse,
+};
+use crate::error::{Error, Result};
+use crate::protocol::{Method, Request, ResponseHead, Trailer};
+use crate::StatusCode;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time;
+
+pub struct AsyncSSFTPClient {
+    server_addr: SocketAddr,
+    timeout: Option<Duration>,
+    /// Negotiated on the first request which needs it.
+    capabilities: Mutex<Option<Capabilities>>,
+}
+
+/// Payload stream of the responses of [`AsyncSSFTPClient`].
+pub type AsyncPayload = Pin<Box<dyn AsyncRead + Send>>;
+
+/// A raw response, see [`AsyncSSFTPClient::request`].
+pub struct AsyncResponse {
+    pub status_code: StatusCode,
+    pub headers: serde_json::Value,
+    pub payload_stream: AsyncPayload,
+}
+
+/// Payload of a GET response, failing at its end like the one of
+/// [`SSFTPClient::get`](crate::client::SSFTPClient::get) if it does not bring the
+/// announced number of bytes.
+struct AsyncFilePayload {
+    payload: AsyncPayload,
+    count: ByteCount,
+}
+
+impl AsyncRead for AsyncFilePayload {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let (filled, remaining) = (buf.filled().len(), buf.remaining());
+        match this.payload.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if remaining > 0 => {
+                let counted = this.count.add(buf.filled().len() - filled);
+                // nothing is read when it fails
+                if counted.is_err() {
+                    buf.set_filled(filled);
+                }
+                Poll::Ready(counted)
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl DirResponse<AsyncPayload> {
+    /// Read the entries lazily as they arrive, see [`AsyncDirEntries::next_entry`].
+    pub fn async_entries(self) -> AsyncDirEntries {
+        AsyncDirEntries {
+            lines: BufReader::new(self.payload).lines(),
+        }
+    }
+}
+
+/// Entries of a [`DirResponse`] read from an async payload.
+pub struct AsyncDirEntries {
+    lines: Lines<BufReader<AsyncPayload>>,
+}
+
+impl AsyncDirEntries {
+    /// Return the next entry, or `None` at the end of the listing.
+    pub async fn next_entry(&mut self) -> io::Result<Option<DirEntry>> {
+        let line = self.lines.next_line().await?;
+        Ok(line.map(|line| DirEntry::from_line(&line)))
+    }
+}
+
+impl AsyncSSFTPClient {
+    pub fn new(host: IpAddr, port: u16) -> Self {
+        AsyncSSFTPClient {
+            server_addr: SocketAddr::new(host, port),
+            timeout: None,
+            capabilities: Mutex::new(None),
+        }
+    }
+
+    /// Give up with [`Error::Timeout`] when connecting and reading the status line and
+    /// headers takes longer than `timeout`. Reading the payload is not limited. Wait
+    /// forever if `None`, the default.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// send a raw request, read and parse the status code and headers, leaving only payload untouched.
+    async fn general_request(&self, request: &Request) -> Result<AsyncResponse> {
+        match self.timeout {
+            Some(timeout) => time::timeout(timeout, self.exchange(request))
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => self.exchange(request).await,
+        }
+    }
+
+    async fn exchange(&self, request: &Request) -> Result<AsyncResponse> {
+        let request = request.encode()?;
+        debug!(
+            server:% = self.server_addr,
+            request:% = String::from_utf8_lossy(&request).trim_end();
+            "sending request"
+        );
+        let mut stream = TcpStream::connect(self.server_addr).await?;
+        stream.write_all(&request).await?;
+        let mut stream = BufReader::new(stream);
+
+        let mut status_line = vec![];
+        stream.read_until(b'\n', &mut status_line).await?;
+        let mut headers_line = vec![];
+        stream.read_until(b'\n', &mut headers_line).await?;
+        let head = ResponseHead::decode(&status_line, &headers_line)?;
+        if let Some(encoding) = content_encoding(&head.headers)? {
+            return Err(Error::Protocol(format!(
+                "payload encoded with {} but no encoding was offered",
+                encoding
+            )));
+        }
+        if is_chunked(&head.headers)? {
+            return Err(Error::Protocol(
+                "chunked payload but chunked framing was not offered".into(),
+            ));
+        }
+
+        debug!(status:% = head.status_code, headers:% = head.headers; "response received");
+        Ok(AsyncResponse {
+            status_code: head.status_code,
+            headers: head.headers,
+            payload_stream: Box::pin(stream),
+        })
+    }
+
+    /// Negotiate the protocol version and learn what the server supports. The first call
+    /// sends a `HELLO` request, the result is reused afterwards.
+    pub async fn capabilities(&self) -> Result<Capabilities> {
+        let cached = self
+            .capabilities
+            .lock()
+            .ok()
+            .and_then(|cached| cached.clone());
+        if let Some(capabilities) = cached {
+            return Ok(capabilities);
+        }
+        let response = self
+            .general_request(&Request::new(Method::Hello, "/"))
+            .await?;
+        let capabilities = Capabilities::from_parts(response.status_code, response.headers)?;
+        debug!(version = capabilities.version; "protocol negotiated");
+        if let Ok(mut cached) = self.capabilities.lock() {
+            *cached = Some(capabilities.clone());
+        }
+        Ok(capabilities)
+    }
+
+    /// send a request with any method and return the raw response, whatever its status code
+    pub async fn request(&self, method: Method, path: &Path) -> Result<AsyncResponse> {
+        if needs_negotiation(method) {
+            self.capabilities().await?.require(method)?;
+        }
+        self.general_request(&Request::new(method, path)).await
+    }
+
+    /// send a get request and return the response. Reading the payload fails with
+    /// [`Error::Truncated`] inside the `io::Error` if it does not bring `content_length`
+    /// bytes, `Error::from` unwraps it
+    pub async fn get(&self, path: &Path) -> Result<GetResponse<AsyncPayload>> {
+        let response = self.request(Method::Get, path).await?;
+        let (status_code, headers) = (response.status_code, response.headers);
+        let response = GetResponse::from_parts(status_code, headers, response.payload_stream)?;
+        // payloads are never chunked, there is no trailer
+        let count = ByteCount::new(response.content_length, Trailer::default());
+        Ok(GetResponse {
+            payload: Box::pin(AsyncFilePayload {
+                payload: response.payload,
+                count,
+            }),
+            ..response
+        })
+    }
+
+    /// send an info request and return the response
+    pub async fn info(&self, path: &Path) -> Result<InfoResponse> {
+        let response = self.request(Method::Info, path).await?;
+        InfoResponse::from_parts(response.status_code, response.headers)
+    }
+
+    /// send a dir request and return the response
+    pub async fn dir(&self, path: &Path) -> Result<DirResponse<AsyncPayload>> {
+        let response = self.request(Method::Dir, path).await?;
+        let (status_code, headers) = (response.status_code, response.headers);
+        let trailer = Trailer::default();
+        DirResponse::from_parts(status_code, headers, response.payload_stream, trailer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+    use tokio::io::AsyncReadExt;
+
+    /// Answer a GET request with `response`, then close the connection, and return what
+    /// the client reads of the payload.
+    fn download(response: &'static [u8]) -> Result<Vec<u8>> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            std::io::BufReader::new(&stream)
+                .read_line(&mut line)
+                .unwrap();
+            assert_eq!(line, "GET /a.txt\n");
+            stream.write_all(response).unwrap();
+        });
+
+        let client = AsyncSSFTPClient::new(Ipv4Addr::LOCALHOST.into(), port);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut payload = client.get(Path::new("/a.txt")).await?.payload;
+            let mut content = vec![];
+            payload.read_to_end(&mut content).await?;
+            Ok(content)
+        })
+    }
+
+    #[test]
+    fn complete_payload() {
+        let content = download(b"OK\n{\"content-length\":5}\nhello").unwrap();
+        assert_eq!(content, b"hello");
+    }
+
+    #[test]
+    fn short_payload_is_truncated() {
+        // what a server sends when the file changes, it holds back the last byte
+        let result = download(b"OK\n{\"content-length\":5}\nhell");
+        assert!(matches!(
+            result,
+            Err(Error::Truncated {
+                expected: 5,
+                received: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn long_payload_is_truncated() {
+        let result = download(b"OK\n{\"content-length\":3}\nhello");
+        assert!(matches!(result, Err(Error::Truncated { expected: 3, .. })));
+    }
+}
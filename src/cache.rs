@@ -0,0 +1,429 @@
+//! On-disk cache of downloaded files, see [`DownloadCache`].
+//!
+//! Each cached file is stored as `<key>` next to `<key>.json`, its server, path,
+//! validators, size, mode and last use. The key is a hash of the server address and the
+//! path. Files are written to a temporary file first and renamed, so several clients can
+//! share a cache directory. Temporary files left by clients which died while writing are
+//! removed when evicting.
+
+use crate::client::{GetResponse, Validators};
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde_json::{json, Map, Value};
+
+/// Age after which a temporary file is considered left by a client which died.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Counters of a [`DownloadCache`] since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Files served from the cache after the server told they did not change.
+    pub hits: u64,
+    /// Files downloaded, because they were not cached or changed on the server.
+    pub misses: u64,
+    /// Files removed to stay within the size budget.
+    pub evictions: u64,
+}
+
+/// A directory keeping copies of downloaded files, see
+/// [`SSFTPClient::set_cache`](crate::client::SSFTPClient::set_cache). A cached file is
+/// revalidated with a conditional GET and only downloaded again if it changed. The
+/// least recently used files are evicted once the cache grows beyond its size budget.
+///
+/// Only files the server sends validators for are cached, there would be no cheap way
+/// to tell whether they changed.
+#[derive(Debug, Clone)]
+pub struct DownloadCache {
+    dir: PathBuf,
+    max_size: u64,
+    stats: Arc<Mutex<CacheStats>>,
+}
+
+/// What is known of a cached file, from its `.json` file.
+#[derive(Debug, Clone)]
+pub(crate) struct CacheEntry {
+    key: String,
+    pub(crate) validators: Validators,
+    size: u64,
+    mode: Option<u32>,
+}
+
+impl DownloadCache {
+    /// Open the cache in `dir`, created if missing, keeping at most `max_size` bytes of
+    /// files.
+    pub fn open<P: Into<PathBuf>>(dir: P, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(DownloadCache {
+            dir,
+            max_size,
+            stats: Arc::default(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.lock().map(|stats| *stats).unwrap_or_default()
+    }
+
+    fn count(&self, update: impl FnOnce(&mut CacheStats)) {
+        if let Ok(mut stats) = self.stats.lock() {
+            update(&mut stats);
+        }
+    }
+
+    /// The cached copy of `path` from `server`, if any.
+    pub(crate) fn lookup(&self, server: SocketAddr, path: &Path) -> Option<CacheEntry> {
+        let key = key(server, path);
+        let content = fs::read(self.dir.join(format!("{}.json", key))).ok()?;
+        let metadata: Map<String, Value> = serde_json::from_slice(&content).ok()?;
+        // keys are hashes, make sure this is the right file
+        if metadata.get("server") != Some(&json!(server.to_string()))
+            || metadata.get("path") != Some(&json!(path.to_string_lossy()))
+        {
+            return None;
+        }
+        Some(CacheEntry {
+            key,
+            validators: Validators {
+                etag: metadata
+                    .get("etag")
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                last_modified: metadata.get("last-modified").and_then(Value::as_u64),
+            },
+            size: metadata.get("size").and_then(Value::as_u64)?,
+            mode: metadata
+                .get("mode")
+                .and_then(Value::as_u64)
+                .map(|mode| mode as u32),
+        })
+    }
+
+    /// Serve a cached file the server told did not change, `None` if it is gone or
+    /// damaged.
+    pub(crate) fn hit(&self, entry: &CacheEntry) -> Option<GetResponse> {
+        let file = File::open(self.dir.join(&entry.key)).ok()?;
+        if file.metadata().ok()?.len() != entry.size {
+            return None;
+        }
+        self.touch(entry);
+        self.count(|stats| stats.hits += 1);
+
+        let mut headers = Map::new();
+        headers.insert("content-length".into(), json!(entry.size));
+        if let Some(etag) = &entry.validators.etag {
+            headers.insert("etag".into(), json!(etag));
+        }
+        if let Some(last_modified) = entry.validators.last_modified {
+            headers.insert("last-modified".into(), json!(last_modified));
+        }
+        if let Some(mode) = entry.mode {
+            headers.insert("mode".into(), json!(mode));
+        }
+        Some(GetResponse {
+            content_length: entry.size,
+            validators: entry.validators.clone(),
+            mode: entry.mode,
+            headers,
+            payload: Box::new(BufReader::new(file)),
+        })
+    }
+
+    /// Record a cached file as just used, it is evicted last.
+    fn touch(&self, entry: &CacheEntry) {
+        let path = self.dir.join(format!("{}.json", entry.key));
+        let metadata = fs::read(&path)
+            .ok()
+            .and_then(|content| serde_json::from_slice::<Map<String, Value>>(&content).ok());
+        if let Some(mut metadata) = metadata {
+            metadata.insert("last-used".into(), json!(now_millis()));
+            let _ = self.write_atomically(&path, &Value::Object(metadata).to_string());
+        }
+    }
+
+    /// Count a download and store the file in the cache as the payload of `response` is
+    /// read. It is only kept once read to the end.
+    pub(crate) fn store(
+        &self,
+        server: SocketAddr,
+        path: &Path,
+        response: GetResponse,
+    ) -> GetResponse {
+        self.count(|stats| stats.misses += 1);
+        if response.validators.is_empty() || response.content_length > self.max_size {
+            return response;
+        }
+        let key = key(server, path);
+        let temp_path = self.temp_path(&key);
+        let temp = match File::create(&temp_path) {
+            Ok(temp) => temp,
+            Err(err) => {
+                debug!(path:% = temp_path.display(); "cannot write to the cache: {}", err);
+                return response;
+            }
+        };
+        let mut metadata = Map::new();
+        metadata.insert("server".into(), json!(server.to_string()));
+        metadata.insert("path".into(), json!(path.to_string_lossy()));
+        if let Some(etag) = &response.validators.etag {
+            metadata.insert("etag".into(), json!(etag));
+        }
+        if let Some(last_modified) = response.validators.last_modified {
+            metadata.insert("last-modified".into(), json!(last_modified));
+        }
+        metadata.insert("size".into(), json!(response.content_length));
+        if let Some(mode) = response.mode {
+            metadata.insert("mode".into(), json!(mode));
+        }
+
+        GetResponse {
+            payload: Box::new(CachingReader {
+                inner: response.payload,
+                cache: self.clone(),
+                key,
+                metadata,
+                temp: Some((temp_path, temp)),
+                written: 0,
+                size: response.content_length,
+            }),
+            ..response
+        }
+    }
+
+    /// A file name for writing to the cache, unique among the clients sharing it.
+    fn temp_path(&self, key: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.dir
+            .join(format!("{}.{}-{}.tmp", key, process::id(), count))
+    }
+
+    fn write_atomically(&self, path: &Path, content: &str) -> io::Result<()> {
+        let temp_path = self.temp_path("meta");
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp_path);
+        })
+    }
+
+    /// Move a fully downloaded file in place, then evict what no longer fits.
+    fn commit(
+        &self,
+        key: &str,
+        temp_path: &Path,
+        mut metadata: Map<String, Value>,
+    ) -> io::Result<()> {
+        fs::rename(temp_path, self.dir.join(key))?;
+        metadata.insert("last-used".into(), json!(now_millis()));
+        let path = self.dir.join(format!("{}.json", key));
+        self.write_atomically(&path, &Value::Object(metadata).to_string())?;
+        self.evict()
+    }
+
+    /// Remove the least recently used files until the cache fits its size budget, and
+    /// the stale temporary files.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+                let age = modified.map(|modified| modified.elapsed().unwrap_or_default());
+                if age.is_ok_and(|age| age > STALE_TEMP_AGE) {
+                    let _ = fs::remove_file(&path);
+                    debug!(path:% = path.display(); "removed a stale temporary file");
+                }
+                continue;
+            }
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let metadata = fs::read(&path)
+                .ok()
+                .and_then(|content| serde_json::from_slice::<Map<String, Value>>(&content).ok());
+            if let Some(metadata) = metadata {
+                let last_used = metadata.get("last-used").and_then(Value::as_u64);
+                let size = metadata.get("size").and_then(Value::as_u64);
+                entries.push((last_used.unwrap_or(0), size.unwrap_or(0), path));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_unstable();
+        for (_, size, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            let _ = fs::remove_file(path.with_extension(""));
+            fs::remove_file(&path)?;
+            total -= size;
+            self.count(|stats| stats.evictions += 1);
+            debug!(path:% = path.display(); "evicted from the cache");
+        }
+        Ok(())
+    }
+}
+
+/// Payload of a downloaded file, copying it to the cache as it is read.
+struct CachingReader<R> {
+    inner: R,
+    cache: DownloadCache,
+    key: String,
+    metadata: Map<String, Value>,
+    /// The file being written, `None` once committed or given up.
+    temp: Option<(PathBuf, File)>,
+    written: u64,
+    size: u64,
+}
+
+impl<R> CachingReader<R> {
+    fn give_up(&mut self) {
+        if let Some((temp_path, _)) = self.temp.take() {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+impl<R: Read> Read for CachingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = match self.inner.read(buf) {
+            Ok(count) => count,
+            Err(err) => {
+                self.give_up();
+                return Err(err);
+            }
+        };
+        if count == 0 {
+            // a payload shorter than announced is not kept
+            if self.written == self.size {
+                if let Some((temp_path, _)) = self.temp.take() {
+                    let metadata = std::mem::take(&mut self.metadata);
+                    if let Err(err) = self.cache.commit(&self.key, &temp_path, metadata) {
+                        debug!("cannot store the file in the cache: {}", err);
+                        let _ = fs::remove_file(temp_path);
+                    }
+                }
+            }
+            self.give_up();
+        } else if let Some((_, temp)) = &mut self.temp {
+            // the cache is best effort, a failure does not fail the download
+            if temp.write_all(&buf[..count]).is_ok() {
+                self.written += count as u64;
+            } else {
+                self.give_up();
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl<R> Drop for CachingReader<R> {
+    /// A payload not read to the end is not kept.
+    fn drop(&mut self) {
+        self.give_up();
+    }
+}
+
+/// Name of the cached copy of `path` from `server`, an FNV-1a hash.
+fn key(server: SocketAddr, path: &Path) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let name = format!("{}{}", server, path.to_string_lossy());
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::thread;
+
+    const SERVER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4000));
+
+    fn cache(name: &str, max_size: u64) -> DownloadCache {
+        let dir = std::env::temp_dir().join(format!("ssftp-cache-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        DownloadCache::open(dir, max_size).unwrap()
+    }
+
+    /// Download `content` as `path` through the cache.
+    fn download(cache: &DownloadCache, path: &str, content: &'static [u8]) {
+        let response = GetResponse {
+            content_length: content.len() as u64,
+            validators: Validators {
+                etag: Some(path.into()),
+                last_modified: None,
+            },
+            mode: None,
+            headers: Map::new(),
+            payload: Box::new(content) as Box<dyn Read>,
+        };
+        let mut response = cache.store(SERVER, Path::new(path), response);
+        io::copy(&mut response.payload, &mut io::sink()).unwrap();
+        // last uses are recorded in milliseconds
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache("lru", 10);
+        download(&cache, "/a", b"aaaaa");
+        download(&cache, "/b", b"bbbbb");
+        let a = cache.lookup(SERVER, Path::new("/a")).unwrap();
+        let mut hit = cache.hit(&a).unwrap();
+        let mut content = vec![];
+        hit.payload.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"aaaaa");
+        thread::sleep(Duration::from_millis(5));
+        download(&cache, "/c", b"ccccc");
+
+        assert!(cache.lookup(SERVER, Path::new("/a")).is_some());
+        assert!(cache.lookup(SERVER, Path::new("/b")).is_none());
+        assert!(cache.lookup(SERVER, Path::new("/c")).is_some());
+        let stats = CacheStats {
+            hits: 1,
+            misses: 3,
+            evictions: 1,
+        };
+        assert_eq!(cache.stats(), stats);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn removes_stale_temporary_files() {
+        let cache = cache("stale", 10);
+        let stale = cache.dir().join("0123456789abcdef.1-0.tmp");
+        let file = File::create(&stale).unwrap();
+        file.set_modified(SystemTime::now() - 2 * STALE_TEMP_AGE)
+            .unwrap();
+        let fresh = cache.dir().join("0123456789abcdef.1-1.tmp");
+        File::create(&fresh).unwrap();
+        cache.evict().unwrap();
+
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
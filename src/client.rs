@@ -0,0 +1,1135 @@
+use crate::cache::DownloadCache;
+use crate::encoding::Encoding;
+use crate::error::{Error, Result};
+use crate::protocol::{
+    ChunkedReader, ErrorDetail, Method, Request, ResponseHead, SortOrder, Trailer, PROTOCOL_VERSION,
+};
+use crate::StatusCode;
+
+use std::io::{self, BufRead, BufReader, Lines, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::debug;
+use serde_json::{Map, Value};
+
+pub struct SSFTPClient {
+    server_addr: SocketAddr,
+    timeout: Option<Duration>,
+    /// Encodings offered for GET and DIR payloads, in order of preference.
+    accept_encoding: Vec<Encoding>,
+    /// Negotiated on the first request which needs it.
+    capabilities: Mutex<Option<Capabilities>>,
+    /// Where GET keeps copies of downloaded files, if anywhere.
+    cache: Option<DownloadCache>,
+}
+
+/// A raw response, see [`SSFTPClient::request`]. The payload is already decoded if the
+/// server compressed or chunked it, the headers are as received.
+pub struct Response {
+    pub status_code: StatusCode,
+    pub headers: serde_json::Value,
+    pub payload_stream: Box<dyn Read>,
+    /// Trailer headers of a chunked payload, known once `payload_stream` is read to the
+    /// end. Always empty for other payloads.
+    pub trailer: Trailer,
+}
+
+/// Type of a file system entry on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A successful response to a GET request. `P` is the payload stream, the content of
+/// the file.
+pub struct GetResponse<P = Box<dyn Read>> {
+    /// Size of the file in bytes, after decoding if it was sent compressed.
+    pub content_length: u64,
+    /// Identify this version of the file, see [`SSFTPClient::get_if_modified`].
+    pub validators: Validators,
+    /// Unix permission bits of the file on the server, `None` if it did not tell.
+    pub mode: Option<u32>,
+    /// All the headers as received, including those unknown to this client.
+    pub headers: Map<String, Value>,
+    pub payload: P,
+}
+
+/// A successful response to an INFO request.
+#[derive(Debug)]
+pub struct InfoResponse {
+    pub kind: EntryKind,
+    /// Size in bytes, only given for files.
+    pub size: Option<u64>,
+    /// Identify the current version of a file, empty for directories.
+    pub validators: Validators,
+    /// All the headers as received, including those unknown to this client.
+    pub headers: Map<String, Value>,
+}
+
+/// What identifies a version of a file, from the `etag` and `last-modified` headers.
+/// Both are `None` with servers which do not send them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    /// Opaque tag which changes whenever the file does.
+    pub etag: Option<String>,
+    /// Modification time in seconds since the Unix epoch.
+    pub last_modified: Option<u64>,
+}
+
+impl Validators {
+    fn from_headers(headers: &Map<String, Value>) -> Result<Self> {
+        let etag = match headers.get("etag") {
+            None => None,
+            Some(Value::String(etag)) => Some(etag.clone()),
+            Some(etag) => {
+                return Err(Error::Protocol(format!(
+                    "header etag is not a string: {}",
+                    etag
+                )))
+            }
+        };
+        let last_modified = match headers.get("last-modified") {
+            None => None,
+            Some(_) => Some(u64_header(headers, "last-modified")?),
+        };
+        Ok(Validators {
+            etag,
+            last_modified,
+        })
+    }
+
+    /// Whether there is anything to compare the file on the server with.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// The request options asking to skip the payload if the file did not change.
+    fn to_options(&self) -> Vec<(&'static str, Value)> {
+        let mut options = vec![];
+        if let Some(etag) = &self.etag {
+            options.push(("if-none-match", etag.as_str().into()));
+        }
+        if let Some(last_modified) = self.last_modified {
+            options.push(("if-modified-since", last_modified.into()));
+        }
+        options
+    }
+}
+
+/// A successful response to a DIR request. `P` is the payload stream, iterate the
+/// entries with `entries`.
+pub struct DirResponse<P = Box<dyn Read>> {
+    /// Number of entries in the directory, `None` if the listing is streamed. It is
+    /// then only known after the last entry, see [`DirEntries::count`].
+    pub count: Option<u64>,
+    /// Size of the listing in bytes, after decoding if it was sent compressed. `None` if
+    /// the listing is streamed.
+    pub content_length: Option<u64>,
+    /// All the headers as received, including those unknown to this client.
+    pub headers: Map<String, Value>,
+    pub payload: P,
+    /// Trailer headers of a streamed listing.
+    pub trailer: Trailer,
+    /// Cursor to ask for the next page of a paginated listing, `None` on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Which part of a directory to ask for, see [`SSFTPClient::dir_page`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirOptions {
+    /// Order of the entries, by name if `None`.
+    pub sort: Option<SortOrder>,
+    /// Largest number of entries in a page, all of them if `None`.
+    pub limit: Option<u64>,
+    /// Start after the page which returned this [`DirResponse::next_cursor`], from the
+    /// first entry if `None`. It must be used with the same sort order.
+    pub cursor: Option<String>,
+    /// List only the entries matching one of these glob patterns, all of them if empty.
+    /// Patterns with a `/` match the path relative to the directory, the others the name.
+    pub include: Vec<String>,
+    /// Leave out the entries matching one of these glob patterns, and what is under them.
+    pub exclude: Vec<String>,
+    /// List the entries of the subdirectories too, their names are then paths relative
+    /// to the directory.
+    pub recursive: bool,
+}
+
+impl DirOptions {
+    /// The request options, only those which are set.
+    fn to_options(&self) -> Vec<(&'static str, Value)> {
+        let mut options = vec![];
+        if let Some(sort) = self.sort {
+            options.push(("sort", sort.as_str().into()));
+        }
+        if let Some(limit) = self.limit {
+            options.push(("limit", limit.into()));
+        }
+        if let Some(cursor) = &self.cursor {
+            options.push(("cursor", cursor.as_str().into()));
+        }
+        if !self.include.is_empty() {
+            options.push(("include", self.include.clone().into()));
+        }
+        if !self.exclude.is_empty() {
+            options.push(("exclude", self.exclude.clone().into()));
+        }
+        if self.recursive {
+            options.push(("recursive", true.into()));
+        }
+        options
+    }
+}
+
+/// What to search for, see [`SSFTPClient::find`]. An entry must match every
+/// criterion which is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FindOptions {
+    /// Glob patterns, one of which the entry must match. Patterns with a `/` match the
+    /// path relative to the searched directory, the others the name.
+    pub name: Vec<String>,
+    /// Smallest size in bytes. Directories never match a size range.
+    pub min_size: Option<u64>,
+    /// Largest size in bytes.
+    pub max_size: Option<u64>,
+    /// Earliest modification time, in seconds since the Unix epoch.
+    pub modified_after: Option<u64>,
+    /// Latest modification time, in seconds since the Unix epoch.
+    pub modified_before: Option<u64>,
+    /// Number of directory levels to walk, 1 for the searched directory only. The
+    /// server may enforce a lower one.
+    pub max_depth: Option<u64>,
+    /// Number of results after which to stop. The server may enforce a lower one.
+    pub max_results: Option<u64>,
+}
+
+impl FindOptions {
+    /// The request options, only those which are set.
+    fn to_options(&self) -> Vec<(&'static str, Value)> {
+        let mut options = vec![];
+        if !self.name.is_empty() {
+            options.push(("name", self.name.clone().into()));
+        }
+        let numbers = [
+            ("min-size", self.min_size),
+            ("max-size", self.max_size),
+            ("modified-after", self.modified_after),
+            ("modified-before", self.modified_before),
+            ("max-depth", self.max_depth),
+            ("max-results", self.max_results),
+        ];
+        for (name, value) in numbers {
+            if let Some(value) = value {
+                options.push((name, value.into()));
+            }
+        }
+        options
+    }
+}
+
+/// A successful response to a FIND request. `P` is the payload stream, iterate the
+/// results with `results`.
+pub struct FindResponse<P = Box<dyn Read>> {
+    /// All the headers as received, including those unknown to this client.
+    pub headers: Map<String, Value>,
+    pub payload: P,
+    /// Trailer headers of streamed results.
+    pub trailer: Trailer,
+}
+
+/// An entry found by a FIND request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindResult {
+    /// Path relative to the searched directory.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size in bytes, only given for files.
+    pub size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+impl FindResult {
+    /// Parse a line of a FIND payload, a JSON object.
+    pub(crate) fn from_line(line: &str) -> io::Result<FindResult> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let result: Map<String, Value> = serde_json::from_str(line)
+            .map_err(|err| invalid(&format!("invalid FIND result: {}", err)))?;
+        let path = match result.get("path") {
+            Some(Value::String(path)) => path.clone(),
+            _ => return Err(invalid("FIND result without a path")),
+        };
+        let kind = match result.get("type").and_then(Value::as_str) {
+            Some("file") => EntryKind::File,
+            Some("directory") => EntryKind::Directory,
+            _ => return Err(invalid("FIND result type is neither file nor directory")),
+        };
+        let mtime = result
+            .get("mtime")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| invalid("FIND result without an mtime"))?;
+        Ok(FindResult {
+            path,
+            kind,
+            size: result.get("size").and_then(Value::as_u64),
+            mtime,
+        })
+    }
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// Name of the entry, or its path relative to the directory in a recursive listing.
+    pub name: String,
+    pub kind: EntryKind,
+}
+
+impl DirEntry {
+    /// Parse a line of a DIR payload, directories end with a `/`.
+    pub(crate) fn from_line(line: &str) -> DirEntry {
+        match line.strip_suffix('/') {
+            Some(name) => DirEntry {
+                name: name.into(),
+                kind: EntryKind::Directory,
+            },
+            None => DirEntry {
+                name: line.into(),
+                kind: EntryKind::File,
+            },
+        }
+    }
+}
+
+/// Take the headers object of an `OK` response apart, or fail with the status code.
+fn ok_headers(status_code: StatusCode, headers: Value) -> Result<Map<String, Value>> {
+    if status_code != StatusCode::OK {
+        return Err(Error::Status(
+            status_code,
+            ErrorDetail::from_headers(&headers),
+        ));
+    }
+    match headers {
+        Value::Object(headers) => Ok(headers),
+        _ => Err(Error::Protocol("headers are not a JSON object".into())),
+    }
+}
+
+/// What the server told about itself in the response to `HELLO`.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// Protocol version agreed on, 0 for servers predating `HELLO`.
+    pub version: u64,
+    /// Methods the server accepts.
+    pub methods: Vec<Method>,
+    /// All the headers of the `HELLO` response, including capabilities unknown to this
+    /// client. Empty for servers predating `HELLO`.
+    pub headers: Map<String, Value>,
+}
+
+impl Capabilities {
+    /// Capabilities of the servers predating `HELLO`, they answer it with `BAD-REQUEST`.
+    pub fn legacy() -> Self {
+        Capabilities {
+            version: 0,
+            methods: Method::ALL
+                .iter()
+                .copied()
+                .filter(|method| method.since_version() == 0)
+                .collect(),
+            headers: Map::new(),
+        }
+    }
+
+    /// Interpret the response to `HELLO` and agree on the newest protocol version
+    /// supported by both sides.
+    pub(crate) fn from_parts(status_code: StatusCode, headers: Value) -> Result<Self> {
+        if status_code == StatusCode::BadRequest {
+            return Ok(Capabilities::legacy());
+        }
+        let headers = ok_headers(status_code, headers)?;
+        let server_version = u64_header(&headers, "version")?;
+        let server_min_version = u64_header(&headers, "min-version")?;
+        let version = server_version.min(PROTOCOL_VERSION);
+        if version < server_min_version {
+            return Err(Error::Unsupported(format!(
+                "server speaks protocol versions {} to {}, this client at most {}",
+                server_min_version, server_version, PROTOCOL_VERSION
+            )));
+        }
+
+        let methods = match headers.get("methods").and_then(Value::as_array) {
+            // methods unknown to this client are left out
+            Some(methods) => methods
+                .iter()
+                .filter_map(|method| method.as_str()?.parse().ok())
+                .collect(),
+            None => return Err(Error::Protocol("missing header methods".into())),
+        };
+        Ok(Capabilities {
+            version,
+            methods,
+            headers,
+        })
+    }
+
+    /// Encodings the server can compress payloads with.
+    pub fn encodings(&self) -> Vec<Encoding> {
+        let encodings = self.headers.get("compression").and_then(Value::as_array);
+        encodings
+            .into_iter()
+            .flatten()
+            .filter_map(|encoding| encoding.as_str()?.parse().ok())
+            .collect()
+    }
+
+    /// Names of the DIR request options the server knows.
+    pub fn dir_options(&self) -> Vec<&str> {
+        let options = self.headers.get("dir-options").and_then(Value::as_array);
+        options
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect()
+    }
+
+    /// Whether the server may send payloads with chunked framing.
+    pub fn chunked(&self) -> bool {
+        let chunked = self.headers.get("chunked").and_then(Value::as_bool);
+        chunked.unwrap_or(false)
+    }
+
+    pub fn supports(&self, method: Method) -> bool {
+        self.methods.contains(&method)
+    }
+
+    /// Fail with [`Error::Unsupported`] if the server does not accept `method`.
+    pub fn require(&self, method: Method) -> Result<()> {
+        if self.supports(method) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported(format!("method {}", method)))
+        }
+    }
+}
+
+/// Whether the support of `method` has to be checked before sending it. `HELLO` is
+/// how support is checked, servers predating it answer `BAD-REQUEST`.
+pub(crate) fn needs_negotiation(method: Method) -> bool {
+    method != Method::Hello && method.since_version() > 0
+}
+
+/// The `content-encoding` header of a response, `None` when the payload is sent as is.
+pub(crate) fn content_encoding(headers: &Value) -> Result<Option<Encoding>> {
+    match headers.get("content-encoding") {
+        Some(encoding) => match encoding.as_str().map(str::parse) {
+            Some(Ok(encoding)) => Ok(Some(encoding)),
+            _ => Err(Error::Protocol(format!(
+                "unknown content-encoding {}",
+                encoding
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Whether the payload has chunked framing, the only transfer encoding there is.
+pub(crate) fn is_chunked(headers: &Value) -> Result<bool> {
+    match headers.get("transfer-encoding") {
+        Some(Value::String(encoding)) if encoding == "chunked" => Ok(true),
+        Some(encoding) => Err(Error::Protocol(format!(
+            "unknown transfer-encoding {}",
+            encoding
+        ))),
+        None => Ok(false),
+    }
+}
+
+/// Length of the decoded payload, which is the `content-length` unless the payload is
+/// compressed.
+fn payload_length(headers: &Map<String, Value>) -> Result<u64> {
+    if headers.contains_key("original-length") {
+        u64_header(headers, "original-length")
+    } else {
+        u64_header(headers, "content-length")
+    }
+}
+
+/// The `mode` header, the permission bits of a file, if it was sent.
+fn mode_header(headers: &Map<String, Value>) -> Result<Option<u32>> {
+    match headers.get("mode") {
+        None => Ok(None),
+        Some(mode) => match mode.as_u64() {
+            Some(mode) if mode <= 0o7777 => Ok(Some(mode as u32)),
+            _ => Err(Error::Protocol(format!(
+                "header mode is not permission bits: {}",
+                mode
+            ))),
+        },
+    }
+}
+
+/// Read a header which must be an unsigned integer.
+fn u64_header(headers: &Map<String, Value>, name: &'static str) -> Result<u64> {
+    match headers.get(name) {
+        Some(value) => value.as_u64().ok_or_else(|| {
+            Error::Protocol(format!(
+                "header {} is not an unsigned integer: {}",
+                name, value
+            ))
+        }),
+        None => Err(Error::Protocol(format!("missing header {}", name))),
+    }
+}
+
+impl<P> GetResponse<P> {
+    pub(crate) fn from_parts(status_code: StatusCode, headers: Value, payload: P) -> Result<Self> {
+        let headers = ok_headers(status_code, headers)?;
+        Ok(GetResponse {
+            content_length: payload_length(&headers)?,
+            validators: Validators::from_headers(&headers)?,
+            mode: mode_header(&headers)?,
+            headers,
+            payload,
+        })
+    }
+}
+
+impl InfoResponse {
+    pub(crate) fn from_parts(status_code: StatusCode, headers: Value) -> Result<Self> {
+        let headers = ok_headers(status_code, headers)?;
+        let (kind, size) = match headers.get("type").and_then(Value::as_str) {
+            Some("file") => (
+                EntryKind::File,
+                Some(u64_header(&headers, "content-length")?),
+            ),
+            Some("directory") => (EntryKind::Directory, None),
+            _ => {
+                return Err(Error::Protocol(
+                    "header type is neither \"file\" nor \"directory\"".into(),
+                ))
+            }
+        };
+        Ok(InfoResponse {
+            kind,
+            size,
+            validators: Validators::from_headers(&headers)?,
+            headers,
+        })
+    }
+}
+
+impl<P> DirResponse<P> {
+    pub(crate) fn from_parts(
+        status_code: StatusCode,
+        headers: Value,
+        payload: P,
+        trailer: Trailer,
+    ) -> Result<Self> {
+        let headers = ok_headers(status_code, headers)?;
+        // a streamed listing only tells its size at the end
+        let (count, content_length) = if headers.contains_key("transfer-encoding") {
+            (None, None)
+        } else {
+            let count = u64_header(&headers, "count")?;
+            (Some(count), Some(payload_length(&headers)?))
+        };
+        let next_cursor = match headers.get("next-cursor") {
+            Some(Value::String(cursor)) => Some(cursor.clone()),
+            Some(cursor) => {
+                return Err(Error::Protocol(format!(
+                    "header next-cursor is not a string: {}",
+                    cursor
+                )))
+            }
+            None => None,
+        };
+        Ok(DirResponse {
+            count,
+            content_length,
+            headers,
+            payload,
+            trailer,
+            next_cursor,
+        })
+    }
+}
+
+impl<P: Read> DirResponse<P> {
+    /// Iterate the entries lazily as they are read from the payload.
+    pub fn entries(self) -> DirEntries<P> {
+        DirEntries {
+            lines: BufReader::new(self.payload).lines(),
+            count: self.count,
+            trailer: self.trailer,
+        }
+    }
+}
+
+/// Iterator over the pages of a directory, see [`SSFTPClient::dir_pages`]. It stops
+/// after the last page or the first error.
+pub struct DirPages<'a> {
+    client: &'a SSFTPClient,
+    path: PathBuf,
+    options: DirOptions,
+    done: bool,
+}
+
+impl Iterator for DirPages<'_> {
+    type Item = Result<DirResponse>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = self.client.dir_page(&self.path, &self.options);
+        match &page {
+            Ok(DirResponse {
+                next_cursor: Some(cursor),
+                ..
+            }) => self.options.cursor = Some(cursor.clone()),
+            _ => self.done = true,
+        }
+        Some(page)
+    }
+}
+
+/// Iterator over the entries of a [`DirResponse`].
+pub struct DirEntries<P> {
+    lines: Lines<BufReader<P>>,
+    count: Option<u64>,
+    trailer: Trailer,
+}
+
+impl<P> DirEntries<P> {
+    /// Number of entries in the directory. For a streamed listing it is sent after the
+    /// entries, so it is `None` until the iterator returned `None`.
+    pub fn count(&self) -> Option<u64> {
+        let trailer = self.trailer.get();
+        let count = trailer.as_ref().and_then(|trailer| trailer.get("count"));
+        self.count.or_else(|| count.and_then(Value::as_u64))
+    }
+}
+
+impl<P: Read> Iterator for DirEntries<P> {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines
+            .next()
+            .map(|line| line.map(|line| DirEntry::from_line(&line)))
+    }
+}
+
+impl<P> FindResponse<P> {
+    pub(crate) fn from_parts(
+        status_code: StatusCode,
+        headers: Value,
+        payload: P,
+        trailer: Trailer,
+    ) -> Result<Self> {
+        Ok(FindResponse {
+            headers: ok_headers(status_code, headers)?,
+            payload,
+            trailer,
+        })
+    }
+}
+
+impl<P: Read> FindResponse<P> {
+    /// Iterate the results lazily as they are read from the payload.
+    pub fn results(self) -> FindResults<P> {
+        FindResults {
+            lines: BufReader::new(self.payload).lines(),
+            headers: self.headers,
+            trailer: self.trailer,
+        }
+    }
+}
+
+/// Iterator over the results of a [`FindResponse`].
+pub struct FindResults<P> {
+    lines: Lines<BufReader<P>>,
+    headers: Map<String, Value>,
+    trailer: Trailer,
+}
+
+impl<P> FindResults<P> {
+    /// A header telling how the search went, from the trailer if the results are
+    /// streamed.
+    fn summary(&self, name: &str) -> Option<Value> {
+        match self.headers.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.trailer.get()?.get(name).cloned(),
+        }
+    }
+
+    /// Number of results. For streamed results it is sent after them, so it is `None`
+    /// until the iterator returned `None`.
+    pub fn count(&self) -> Option<u64> {
+        self.summary("count").as_ref().and_then(Value::as_u64)
+    }
+
+    /// Why the server stopped the search before walking the whole subtree, e.g.
+    /// `time-limit` or `max-results`, `None` if it did not. For streamed results it is
+    /// only known once the iterator returned `None`.
+    pub fn truncated(&self) -> Option<String> {
+        match self.summary("truncated") {
+            Some(Value::String(reason)) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl<P: Read> Iterator for FindResults<P> {
+    type Item = io::Result<FindResult>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines
+            .next()
+            .map(|line| line.and_then(|line| FindResult::from_line(&line)))
+    }
+}
+
+/// A GET response whose payload fails at its end if it did not bring the announced
+/// number of bytes, or if the server tells in the trailer that the file changed during
+/// the transfer. The error is then an [`Error`] wrapped in the `io::Error`.
+fn file_response(response: Response) -> Result<GetResponse> {
+    let trailer = response.trailer;
+    let response = GetResponse::from_parts(
+        response.status_code,
+        response.headers,
+        response.payload_stream,
+    )?;
+    Ok(GetResponse {
+        payload: Box::new(FilePayload {
+            count: ByteCount::new(response.content_length, trailer),
+            payload: response.payload,
+        }),
+        ..response
+    })
+}
+
+struct FilePayload {
+    payload: Box<dyn Read>,
+    count: ByteCount,
+}
+
+impl Read for FilePayload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.payload.read(buf)?;
+        if !buf.is_empty() {
+            self.count.add(read)?;
+        }
+        Ok(read)
+    }
+}
+
+/// Count the bytes of the payload of a GET response, to fail if it does not bring the
+/// file as announced by its headers and trailer.
+pub(crate) struct ByteCount {
+    trailer: Trailer,
+    /// Decoded length of the file, from the headers.
+    expected: u64,
+    received: u64,
+}
+
+impl ByteCount {
+    pub(crate) fn new(expected: u64, trailer: Trailer) -> Self {
+        ByteCount {
+            trailer,
+            expected,
+            received: 0,
+        }
+    }
+
+    /// Count `read` more bytes, `0` meaning the end of the payload.
+    pub(crate) fn add(&mut self, read: usize) -> io::Result<()> {
+        self.received += read as u64;
+        if self.received > self.expected {
+            return Err(self.truncated(io::ErrorKind::InvalidData));
+        }
+        if read > 0 {
+            return Ok(());
+        }
+        let trailer = self.trailer.get();
+        let changed = trailer
+            .as_ref()
+            .and_then(|trailer| trailer.get("file-changed"));
+        if changed == Some(&Value::Bool(true)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file changed on the server during the transfer",
+            ));
+        }
+        if self.received < self.expected {
+            return Err(self.truncated(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(())
+    }
+
+    fn truncated(&self, kind: io::ErrorKind) -> io::Error {
+        let err = Error::Truncated {
+            expected: self.expected,
+            received: self.received,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+impl SSFTPClient {
+    pub fn new(host: IpAddr, port: u16) -> Self {
+        SSFTPClient {
+            server_addr: SocketAddr::new(host, port),
+            timeout: None,
+            accept_encoding: Encoding::ALL.to_vec(),
+            capabilities: Mutex::new(None),
+            cache: None,
+        }
+    }
+
+    /// Keep copies of the files downloaded with [`SSFTPClient::get`] in `cache`, and
+    /// only download them again if they changed. No cache if `None`, the default.
+    pub fn set_cache(&mut self, cache: Option<DownloadCache>) {
+        self.cache = cache;
+    }
+
+    pub fn cache(&self) -> Option<&DownloadCache> {
+        self.cache.as_ref()
+    }
+
+    /// Offer these encodings, in order of preference, for the server to compress GET
+    /// and DIR payloads with. All of them by default, none disables compression.
+    pub fn set_accept_encoding(&mut self, encodings: Vec<Encoding>) {
+        self.accept_encoding = encodings;
+    }
+
+    /// Give up with [`Error::Timeout`] when connecting, or any single read or write on
+    /// the connection, takes longer than `timeout`. Wait forever if `None`, the default.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Send a request, return a tcp stream
+    fn connect_and_send(&self, request: &Request) -> Result<BufReader<TcpStream>> {
+        let request = request.encode()?;
+        debug!(
+            server:% = self.server_addr,
+            request:% = String::from_utf8_lossy(&request).trim_end();
+            "sending request"
+        );
+        let mut stream = match self.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&self.server_addr, timeout)?,
+            None => TcpStream::connect(self.server_addr)?,
+        };
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        stream.write_all(&request)?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// send a request, read and parse the status code and headers, leaving only payload untouched.
+    fn general_request(&self, request: &Request) -> Result<Response> {
+        let mut stream = self.connect_and_send(request)?;
+        let head = ResponseHead::read_from(&mut stream)?;
+        debug!(status:% = head.status_code, headers:% = head.headers; "response received");
+        let (stream, trailer): (Box<dyn Read>, _) = if is_chunked(&head.headers)? {
+            let reader = ChunkedReader::new(stream);
+            let trailer = reader.trailer();
+            (Box::new(reader), trailer)
+        } else {
+            (Box::new(stream), Trailer::default())
+        };
+        let payload_stream = match content_encoding(&head.headers)? {
+            Some(encoding) => encoding.decoder(stream)?,
+            None => stream,
+        };
+        Ok(Response {
+            status_code: head.status_code,
+            headers: head.headers,
+            payload_stream,
+            trailer,
+        })
+    }
+
+    /// Negotiate the protocol version and learn what the server supports. The first call
+    /// sends a `HELLO` request, the result is reused afterwards.
+    pub fn capabilities(&self) -> Result<Capabilities> {
+        let cached = self
+            .capabilities
+            .lock()
+            .ok()
+            .and_then(|cached| cached.clone());
+        if let Some(capabilities) = cached {
+            return Ok(capabilities);
+        }
+        let response = self.general_request(&Request::new(Method::Hello, "/"))?;
+        let capabilities = Capabilities::from_parts(response.status_code, response.headers)?;
+        debug!(version = capabilities.version; "protocol negotiated");
+        if let Ok(mut cached) = self.capabilities.lock() {
+            *cached = Some(capabilities.clone());
+        }
+        Ok(capabilities)
+    }
+
+    /// send a request with any method and return the raw response, whatever its status code
+    pub fn request(&self, method: Method, path: &Path) -> Result<Response> {
+        let request = self.new_request(method, path)?;
+        self.general_request(&request)
+    }
+
+    /// Build a request, with the options to negotiate how the payload is sent.
+    fn new_request(&self, method: Method, path: &Path) -> Result<Request> {
+        if needs_negotiation(method) {
+            self.capabilities()?.require(method)?;
+        }
+        let mut request = Request::new(method, path);
+        let has_payload = matches!(method, Method::Get | Method::Dir | Method::Find);
+        if has_payload && self.capabilities()?.chunked() {
+            request = request.with_option("accept-chunked", true);
+        }
+        if has_payload && !self.accept_encoding.is_empty() {
+            let supported = self.capabilities()?.encodings();
+            let encodings: Vec<&str> = self
+                .accept_encoding
+                .iter()
+                .filter(|encoding| supported.contains(encoding))
+                .map(Encoding::as_str)
+                .collect();
+            if !encodings.is_empty() {
+                request = request.with_option("accept-encoding", encodings);
+            }
+        }
+        Ok(request)
+    }
+
+    /// send a get request and return the response. With a cache, a cached copy is
+    /// revalidated and returned if the file did not change. Reading the payload fails
+    /// with [`Error::Truncated`] inside the `io::Error` if it does not bring
+    /// `content_length` bytes, `Error::from` unwraps it
+    pub fn get(&self, path: &Path) -> Result<GetResponse> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.get_uncached(path),
+        };
+        let response = match cache.lookup(self.server_addr, path) {
+            Some(entry) => match self.get_if_modified(path, &entry.validators)? {
+                Some(response) => response,
+                None => match cache.hit(&entry) {
+                    Some(response) => {
+                        debug!(path:% = path.display(); "served from the cache");
+                        return Ok(response);
+                    }
+                    // the cached copy is gone
+                    None => self.get_uncached(path)?,
+                },
+            },
+            None => self.get_uncached(path)?,
+        };
+        Ok(cache.store(self.server_addr, path, response))
+    }
+
+    fn get_uncached(&self, path: &Path) -> Result<GetResponse> {
+        file_response(self.request(Method::Get, path)?)
+    }
+
+    /// send a get request unless the local copy identified by `validators` is still
+    /// current, and return the response. `None` means the file did not change. Servers
+    /// not supporting conditional requests always send the file
+    ///
+    /// ```no_run
+    /// use ssftp::client::SSFTPClient;
+    /// use std::path::Path;
+    ///
+    /// let client = SSFTPClient::new("127.0.0.1".parse().unwrap(), 8000);
+    /// let path = Path::new("/toolchain.tar.zst");
+    /// let validators = client.get(path).unwrap().validators;
+    /// // later on
+    /// match client.get_if_modified(path, &validators).unwrap() {
+    ///     Some(response) => println!("changed, {} bytes", response.content_length),
+    ///     None => println!("still current"),
+    /// }
+    /// ```
+    pub fn get_if_modified(
+        &self,
+        path: &Path,
+        validators: &Validators,
+    ) -> Result<Option<GetResponse>> {
+        let mut request = self.new_request(Method::Get, path)?;
+        // servers predating options reject a request carrying them, they get a plain
+        // GET and send the file
+        if self.capabilities()?.version > 0 {
+            for (name, value) in validators.to_options() {
+                request = request.with_option(name, value);
+            }
+        }
+        let response = self.general_request(&request)?;
+        if response.status_code == StatusCode::NotModified {
+            return Ok(None);
+        }
+        file_response(response).map(Some)
+    }
+
+    /// send an info request and return the response
+    pub fn info(&self, path: &Path) -> Result<InfoResponse> {
+        let response = self.request(Method::Info, path)?;
+        InfoResponse::from_parts(response.status_code, response.headers)
+    }
+
+    /// send a dir request and return the response
+    pub fn dir(&self, path: &Path) -> Result<DirResponse> {
+        let response = self.request(Method::Dir, path)?;
+        let (status_code, headers) = (response.status_code, response.headers);
+        DirResponse::from_parts(
+            status_code,
+            headers,
+            response.payload_stream,
+            response.trailer,
+        )
+    }
+
+    /// send a dir request for part of a directory, a page or the entries matching
+    /// patterns, and return the response. Fail with [`Error::Unsupported`] if the
+    /// server does not know one of the options
+    pub fn dir_page(&self, path: &Path, options: &DirOptions) -> Result<DirResponse> {
+        let mut request = self.new_request(Method::Dir, path)?;
+        let capabilities = self.capabilities()?;
+        let supported = capabilities.dir_options();
+        for (name, value) in options.to_options() {
+            if !supported.contains(&name) {
+                return Err(Error::Unsupported(format!("DIR option {}", name)));
+            }
+            request = request.with_option(name, value);
+        }
+        let response = self.general_request(&request)?;
+        let (status_code, headers) = (response.status_code, response.headers);
+        DirResponse::from_parts(
+            status_code,
+            headers,
+            response.payload_stream,
+            response.trailer,
+        )
+    }
+
+    /// send a find request searching the subtree under `path` and return the response.
+    /// Fail with [`Error::Unsupported`] if the server does not know FIND
+    ///
+    /// ```no_run
+    /// use ssftp::client::{FindOptions, SSFTPClient};
+    /// use std::path::Path;
+    ///
+    /// let client = SSFTPClient::new("127.0.0.1".parse().unwrap(), 8000);
+    /// let options = FindOptions {
+    ///     name: vec!["*.parquet".into()],
+    ///     min_size: Some(1 << 20),
+    ///     ..FindOptions::default()
+    /// };
+    /// let mut results = client.find(Path::new("/datasets"), &options).unwrap().results();
+    /// for result in &mut results {
+    ///     println!("{}", result.unwrap().path);
+    /// }
+    /// if let Some(reason) = results.truncated() {
+    ///     println!("the search stopped early: {}", reason);
+    /// }
+    /// ```
+    pub fn find(&self, path: &Path, options: &FindOptions) -> Result<FindResponse> {
+        let mut request = self.new_request(Method::Find, path)?;
+        for (name, value) in options.to_options() {
+            request = request.with_option(name, value);
+        }
+        let response = self.general_request(&request)?;
+        let (status_code, headers) = (response.status_code, response.headers);
+        FindResponse::from_parts(
+            status_code,
+            headers,
+            response.payload_stream,
+            response.trailer,
+        )
+    }
+
+    /// Iterate the pages of a directory, each one is requested once the previous one
+    /// is returned.
+    ///
+    /// ```no_run
+    /// use ssftp::client::{DirOptions, SSFTPClient};
+    /// use ssftp::protocol::SortOrder;
+    /// use std::path::Path;
+    ///
+    /// let client = SSFTPClient::new("127.0.0.1".parse().unwrap(), 8000);
+    /// let options = DirOptions {
+    ///     sort: Some(SortOrder::Size),
+    ///     limit: Some(1000),
+    ///     ..DirOptions::default()
+    /// };
+    /// for page in client.dir_pages(Path::new("/datasets"), options) {
+    ///     for entry in page.unwrap().entries() {
+    ///         println!("{}", entry.unwrap().name);
+    ///     }
+    /// }
+    /// ```
+    pub fn dir_pages(&self, path: &Path, options: DirOptions) -> DirPages<'_> {
+        DirPages {
+            client: self,
+            path: path.to_path_buf(),
+            options,
+            done: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Serve `count` connections with a server speaking protocol version 0, which only
+    /// knows `<method> <path>` request lines. Send the request lines it got to the
+    /// returned channel.
+    fn legacy_server(count: usize) -> (SSFTPClient, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                let response: &[u8] = match line.as_str() {
+                    "GET /a.txt" => b"OK\n{\"content-length\":5}\nhello",
+                    _ => b"BAD-REQUEST\n{}\n",
+                };
+                stream.write_all(response).unwrap();
+                sender.send(line).unwrap();
+            }
+        });
+        let client = SSFTPClient::new(Ipv4Addr::LOCALHOST.into(), port);
+        (client, receiver)
+    }
+
+    #[test]
+    fn get_if_modified_sends_plain_get_to_legacy_server() {
+        let (client, requests) = legacy_server(2);
+        let validators = Validators {
+            etag: Some("1-5-0".into()),
+            last_modified: Some(0),
+        };
+        let response = client
+            .get_if_modified(Path::new("/a.txt"), &validators)
+            .unwrap()
+            .expect("a legacy server always sends the file");
+        assert_eq!(response.content_length, 5);
+        let mut payload = String::new();
+        let mut reader = response.payload;
+        reader.read_to_string(&mut payload).unwrap();
+        assert_eq!(payload, "hello");
+
+        let requests: Vec<String> = requests.iter().collect();
+        assert_eq!(requests, ["HELLO /", "GET /a.txt"]);
+    }
+}
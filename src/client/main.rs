@@ -1,104 +1,310 @@
-mod client;
-use client::SSFTPClient;
-use ssftp::StatusCode;
+use ssftp::cache::DownloadCache;
+use ssftp::client::{DirOptions, EntryKind, FindOptions, GetResponse, SSFTPClient, Validators};
+use ssftp::logging::{self, log_format_validator, log_level_validator, LogFormat};
 use ssftp::utils::socket_addr_validator;
 
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
 use std::net::SocketAddr;
 use std::path::Path;
+use std::process;
+use std::time::{Duration, UNIX_EPOCH};
 
 // cli arguments
 use clap::{App, Arg, ArgMatches};
 
+use log::LevelFilter;
 
 /// Perform a get request
-fn run_get(client: SSFTPClient, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+fn run_get(mut client: SSFTPClient, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let remote_path = Path::new(matches.value_of("remote-path").unwrap());
     let local_path = Path::new(matches.value_of("local-path").unwrap());
-    let mut response = client.get(Path::new(remote_path))?;
-
-    if let StatusCode::OK = response.status_code {
-        let content_length = response
-            .headers
-            .get("content-length")
-            .unwrap()
-            .as_i64()
-            .unwrap();
-        let mut output_stream = BufWriter::new(File::create(local_path)?);
-        std::io::copy(response.payload_stream.as_mut(), &mut output_stream)?;
+    if let Some(cache_dir) = matches.value_of("cache-dir") {
+        let max_size = matches
+            .value_of("cache-size")
+            .map_or(1 << 30, |bytes| bytes.parse().unwrap());
+        let cache = DownloadCache::open(cache_dir, max_size)
+            .map_err(|err| format!("cache directory {}: {}", cache_dir, err))?;
+        client.set_cache(Some(cache));
+    }
+    let validators = Validators {
+        etag: matches.value_of("if-none-match").map(String::from),
+        last_modified: matches
+            .value_of("if-modified-since")
+            .map(|seconds| seconds.parse().unwrap()),
+    };
+    let mut response = if validators.is_empty() {
+        client.get(Path::new(remote_path))?
+    } else {
+        match client.get_if_modified(Path::new(remote_path), &validators)? {
+            Some(response) => response,
+            None => {
+                println!("The file is not modified, nothing downloaded");
+                return Ok(());
+            }
+        }
+    };
+
+    save(&mut response, local_path, matches)?;
+    let cache_hits = client.cache().map_or(0, |cache| cache.stats().hits);
+    if cache_hits > 0 {
         println!(
-            "The file with {} bytes successfully downloaded to {}",
-            content_length,
+            "The file with {} bytes is unchanged, copied from the cache to {}",
+            response.content_length,
             local_path.to_str().unwrap(),
         );
     } else {
         println!(
-            "Response status code is not OK: {}",
-            response.status_code.to_string()
+            "The file with {} bytes successfully downloaded to {}",
+            response.content_length,
+            local_path.to_str().unwrap(),
         );
     }
+    if let Some(etag) = response.validators.etag {
+        println!("ETag: {}", etag);
+    }
+
+    Ok(())
+}
+
+/// Write the file of `response` to `local_path` through a temporary sibling file,
+/// renamed into place once the whole file arrived and is on disk, so that `local_path`
+/// never holds an incomplete file.
+fn save(
+    response: &mut GetResponse,
+    local_path: &Path,
+    matches: &ArgMatches,
+) -> Result<(), Box<dyn Error>> {
+    let file_name = local_path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file path", local_path.display()))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.part", process::id()));
+    let temp_path = local_path.with_file_name(temp_name);
+
+    if let Err(err) = write_file(response, &temp_path, matches.is_present("preserve")) {
+        let mut partial_path = local_path.as_os_str().to_owned();
+        partial_path.push(".part");
+        if matches.is_present("keep-partial") && fs::rename(&temp_path, &partial_path).is_ok() {
+            eprintln!(
+                "The incomplete file is kept in {}",
+                Path::new(&partial_path).display()
+            );
+        } else {
+            let _ = fs::remove_file(&temp_path);
+        }
+        return Err(err.into());
+    }
+    if let Err(err) = fs::rename(&temp_path, local_path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err.into());
+    }
+    sync_parent(local_path)?;
+    Ok(())
+}
+
+/// Write the file of `response` to `path` and flush it to disk, with the modification
+/// time and permissions of the remote file if `preserve`.
+fn write_file(response: &mut GetResponse, path: &Path, preserve: bool) -> Result<(), ssftp::Error> {
+    let mut output_stream = BufWriter::new(File::create(path)?);
+    io::copy(&mut response.payload, &mut output_stream)?;
+    let file = output_stream
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    if preserve {
+        if let Some(mode) = response.mode {
+            set_mode(&file, mode)?;
+        }
+        if let Some(last_modified) = response.validators.last_modified {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(last_modified))?;
+        }
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Only the permission bits are applied, the setuid, setgid and sticky bits sent by the
+/// server are dropped so that it cannot make the client create privileged files.
+#[cfg(unix)]
+fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode & 0o777))
+}
 
+#[cfg(not(unix))]
+fn set_mode(_file: &File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Flush the rename of a file to disk, by syncing its directory.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
     Ok(())
 }
 
 /// Perform a ir request
 fn run_dir(client: SSFTPClient, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let remote_path = Path::new(matches.value_of("remote-path").unwrap());
-    let response = client.dir(remote_path)?;
+    let options = DirOptions {
+        sort: matches.value_of("sort").map(|sort| sort.parse().unwrap()),
+        limit: matches
+            .value_of("limit")
+            .map(|limit| limit.parse().unwrap()),
+        cursor: matches.value_of("cursor").map(String::from),
+        include: patterns(matches, "include"),
+        exclude: patterns(matches, "exclude"),
+        recursive: matches.is_present("recursive"),
+    };
+    let response = if options == DirOptions::default() {
+        client.dir(remote_path)?
+    } else {
+        client.dir_page(remote_path, &options)?
+    };
+    let next_cursor = response.next_cursor.clone();
+
+    for entry in response.entries() {
+        let entry = entry?;
+        match entry.kind {
+            EntryKind::File => println!("{}", entry.name),
+            EntryKind::Directory => println!("{}/", entry.name),
+        }
+    }
+    // not on stdout, which only lists the entries
+    if let Some(cursor) = next_cursor {
+        eprintln!("More entries, continue with --cursor {:?}", cursor);
+    }
+
+    Ok(())
+}
+
+/// Perform a find request
+fn run_find(client: SSFTPClient, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let remote_path = Path::new(matches.value_of("remote-path").unwrap());
+    let number = |name| matches.value_of(name).map(|value| value.parse().unwrap());
+    let options = FindOptions {
+        name: patterns(matches, "name"),
+        min_size: number("min-size"),
+        max_size: number("max-size"),
+        modified_after: number("modified-after"),
+        modified_before: number("modified-before"),
+        max_depth: number("max-depth"),
+        max_results: number("max-results"),
+    };
+    let mut results = client.find(remote_path, &options)?.results();
 
-    if let StatusCode::OK = response.status_code {
-        let reader = BufReader::new(response.payload_stream);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                println!("{}", line)
+    // one result per line: size (- for directories), modification time, path
+    for result in &mut results {
+        let result = result?;
+        match (result.kind, result.size) {
+            (EntryKind::File, Some(size)) => {
+                println!("{:>12}  {:>10}  {}", size, result.mtime, result.path)
             }
+            _ => println!("{:>12}  {:>10}  {}/", "-", result.mtime, result.path),
         }
-    } else {
-        println!(
-            "Response status code is not OK: {}",
-            response.status_code.to_string()
-        );
+    }
+    // not on stdout, which only lists the results
+    if let Some(reason) = results.truncated() {
+        eprintln!("The server stopped the search early: {}", reason);
     }
 
     Ok(())
 }
 
+/// Values of an option given any number of times.
+fn patterns(matches: &ArgMatches, name: &str) -> Vec<String> {
+    let values = matches.values_of(name).into_iter().flatten();
+    values.map(String::from).collect()
+}
+
 /// Perform an info request
 fn run_info(client: SSFTPClient, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let remote_path = Path::new(matches.value_of("remote-path").unwrap());
     let response = client.info(remote_path)?;
 
-    if let StatusCode::OK = response.status_code {
-        let file_type = response.headers.get("type").unwrap().as_str().unwrap();
-        match file_type {
-            "file" => {
-                let content_length = response
-                    .headers
-                    .get("content-length")
-                    .unwrap()
-                    .as_u64()
-                    .unwrap();
-                println!(
-                    "INFO: {} is a file with {} bytes",
-                    remote_path.to_str().unwrap(),
-                    content_length
-                );
-            }
-            "directory" => println!("INFO: {} is a directory", remote_path.to_str().unwrap()),
-            _ => println!("Malformed info response"),
-        }
+    match (response.kind, response.size) {
+        (EntryKind::File, Some(size)) => println!(
+            "INFO: {} is a file with {} bytes",
+            remote_path.to_str().unwrap(),
+            size
+        ),
+        _ => println!("INFO: {} is a directory", remote_path.to_str().unwrap()),
+    }
+    if let Some(etag) = response.validators.etag {
+        println!("ETag: {}", etag);
+    }
+
+    Ok(())
+}
+
+/// Perform a hello request
+fn run_hello(client: SSFTPClient) -> Result<(), Box<dyn Error>> {
+    let capabilities = client.capabilities()?;
+
+    if capabilities.version == 0 {
+        println!("HELLO: the server predates protocol version negotiation");
     } else {
-        println!(
-            "Response status code is not OK: {}",
-            response.status_code.to_string()
-        );
+        println!("HELLO: protocol version {}", capabilities.version);
+    }
+    let methods: Vec<&str> = capabilities.methods.iter().map(|m| m.as_str()).collect();
+    println!("methods: {}", methods.join(" "));
+    for (name, value) in capabilities.headers.iter() {
+        if !["version", "min-version", "methods"].contains(&name.as_str()) {
+            println!("{}: {}", name, value);
+        }
     }
 
     Ok(())
 }
 
+/// Report a status code other than OK as an outcome of the request rather than a failure.
+fn report_status(result: Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    match result {
+        Err(err) => match err.downcast_ref::<ssftp::Error>() {
+            Some(ssftp::Error::Status(status_code, detail)) => {
+                println!("Response status code is not OK: {}", status_code);
+                if let Some(detail) = detail {
+                    println!("{}: {}", detail.reason, detail.message);
+                }
+                Ok(())
+            }
+            _ => Err(err),
+        },
+        ok => ok,
+    }
+}
+
+fn seconds_validator(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(()),
+        _ => Err("Invalid number of seconds".into()),
+    }
+}
+
+fn number_validator(s: String) -> Result<(), String> {
+    s.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("Invalid number: {}", s))
+}
+
+fn count_validator(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(()),
+        _ => Err("Invalid count".into()),
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let app = App::new("SSFTP client")
         .version("1.0")
@@ -113,6 +319,37 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .index(1)
                 .validator(socket_addr_validator),
         )
+        .arg(
+            Arg::with_name("log-level")
+                .help("most verbose level of log records to print")
+                .value_name("level")
+                .long("log-level")
+                .default_value("warn")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .validator(log_level_validator),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("format of log records")
+                .value_name("format")
+                .long("log-format")
+                .default_value("human")
+                .possible_values(&["human", "json"])
+                .validator(log_format_validator),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("seconds to wait for the server before giving up")
+                .value_name("seconds")
+                .long("timeout")
+                .takes_value(true)
+                .validator(seconds_validator),
+        )
+        .arg(
+            Arg::with_name("no-compression")
+                .help("do not ask the server to compress payloads")
+                .long("no-compression"),
+        )
         .subcommand(
             App::new("get")
                 .about("Send a GET request")
@@ -127,15 +364,174 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("path to save the file on local maching")
                         .required(true)
                         .index(2),
+                )
+                .arg(
+                    Arg::with_name("if-none-match")
+                        .help("download only if the ETag of the file is not this one")
+                        .value_name("etag")
+                        .long("if-none-match")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("if-modified-since")
+                        .help("download only if the file was modified after this Unix time")
+                        .value_name("seconds")
+                        .long("if-modified-since")
+                        .takes_value(true)
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("cache-dir")
+                        .help("keep downloaded files in this directory, download again only changed ones")
+                        .value_name("path")
+                        .long("cache-dir")
+                        .takes_value(true)
+                        .conflicts_with_all(&["if-none-match", "if-modified-since"]),
+                )
+                .arg(
+                    Arg::with_name("cache-size")
+                        .help("most bytes of files kept in the cache directory [default: 1073741824]")
+                        .value_name("bytes")
+                        .long("cache-size")
+                        .takes_value(true)
+                        .requires("cache-dir")
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("keep-partial")
+                        .help("keep what was received of a file whose download failed, as <local-path>.part")
+                        .long("keep-partial"),
+                )
+                .arg(
+                    Arg::with_name("preserve")
+                        .help("give the file the modification time and permissions it has on the server")
+                        .long("preserve"),
                 ),
         )
         .subcommand(
-            App::new("dir").about("Send a DIR request").arg(
-                Arg::with_name("remote-path")
-                    .help("requested file path on server")
-                    .required(true)
-                    .index(1),
-            ),
+            App::new("dir")
+                .about("Send a DIR request")
+                .arg(
+                    Arg::with_name("remote-path")
+                        .help("requested file path on server")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .help("order of the entries")
+                        .long("sort")
+                        .takes_value(true)
+                        .possible_values(&["name", "size", "mtime"]),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .help("list at most this many entries")
+                        .value_name("count")
+                        .long("limit")
+                        .takes_value(true)
+                        .validator(count_validator),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .help("continue the listing where a previous page stopped")
+                        .long("cursor")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .help("list only the entries matching this glob pattern")
+                        .value_name("pattern")
+                        .long("include")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .help("leave out the entries matching this glob pattern")
+                        .value_name("pattern")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .help("list the subdirectories too")
+                        .short("r")
+                        .long("recursive"),
+                ),
+        )
+        .subcommand(
+            App::new("find")
+                .about("Send a FIND request, searching a directory and its subdirectories")
+                .arg(
+                    Arg::with_name("remote-path")
+                        .help("directory to search on server")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("find only the entries matching this glob pattern")
+                        .value_name("pattern")
+                        .long("name")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("min-size")
+                        .help("find only files of at least this many bytes")
+                        .value_name("bytes")
+                        .long("min-size")
+                        .takes_value(true)
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("max-size")
+                        .help("find only files of at most this many bytes")
+                        .value_name("bytes")
+                        .long("max-size")
+                        .takes_value(true)
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("modified-after")
+                        .help("find only entries modified at or after this Unix time")
+                        .value_name("seconds")
+                        .long("modified-after")
+                        .takes_value(true)
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("modified-before")
+                        .help("find only entries modified at or before this Unix time")
+                        .value_name("seconds")
+                        .long("modified-before")
+                        .takes_value(true)
+                        .validator(number_validator),
+                )
+                .arg(
+                    Arg::with_name("max-depth")
+                        .help("walk at most this many directory levels")
+                        .value_name("levels")
+                        .long("max-depth")
+                        .takes_value(true)
+                        .validator(count_validator),
+                )
+                .arg(
+                    Arg::with_name("max-results")
+                        .help("stop after this many results")
+                        .value_name("count")
+                        .long("max-results")
+                        .takes_value(true)
+                        .validator(count_validator),
+                ),
+        )
+        .subcommand(
+            App::new("hello").about("Show the protocol version and capabilities of the server"),
         )
         .subcommand(
             App::new("info").about("Send a INFO request").arg(
@@ -147,18 +543,30 @@ fn main() -> Result<(), Box<dyn Error>> {
         );
 
     let matches = app.get_matches();
+    let log_level: LevelFilter = matches.value_of("log-level").unwrap().parse().unwrap();
+    let log_format: LogFormat = matches.value_of("log-format").unwrap().parse().unwrap();
+    logging::init(log_level, log_format)?;
+
     let socket_addr: SocketAddr = matches.value_of("host").unwrap().parse().unwrap();
-    let ssftp_client = SSFTPClient::new(socket_addr.ip(), socket_addr.port());
+    let mut ssftp_client = SSFTPClient::new(socket_addr.ip(), socket_addr.port());
+    if let Some(seconds) = matches.value_of("timeout") {
+        ssftp_client.set_timeout(Some(Duration::from_secs(seconds.parse().unwrap())));
+    }
+    if matches.is_present("no-compression") {
+        ssftp_client.set_accept_encoding(vec![]);
+    }
 
-    match matches.subcommand() {
+    report_status(match matches.subcommand() {
         ("get", Some(sub)) => run_get(ssftp_client, sub),
         ("dir", Some(sub)) => run_dir(ssftp_client, sub),
+        ("find", Some(sub)) => run_find(ssftp_client, sub),
         ("info", Some(sub)) => run_info(ssftp_client, sub),
+        ("hello", Some(_)) => run_hello(ssftp_client),
         _ => {
             println!("No subcommand, try --help");
             Ok(())
         }
-    }?;
+    })?;
 
     Ok(())
 }
@@ -0,0 +1,172 @@
+//! Compression of payloads, negotiated with the `accept-encoding` request option and
+//! announced with the `content-encoding` response header.
+//!
+//! ```
+//! use ssftp::encoding::Encoding;
+//! use std::io::Read;
+//!
+//! let text = "lorem ipsum dolor sit amet ".repeat(100);
+//! for encoding in Encoding::ALL.iter() {
+//!     let compressed = encoding.compress(text.as_bytes()).unwrap();
+//!     assert!(compressed.len() < text.len());
+//!
+//!     let mut decoded = String::new();
+//!     encoding.decoder(&compressed[..]).unwrap().read_to_string(&mut decoded).unwrap();
+//!     assert_eq!(decoded, text);
+//! }
+//! ```
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::Value;
+
+/// Payloads smaller than this are not worth compressing.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Size of the prefix compressed first to guess whether the whole payload compresses.
+pub const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Content encoding of a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Every encoding, in the order the client prefers them.
+    pub const ALL: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+    /// The encoding as it appears in headers and options.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+
+    /// Compress `data` only if it makes the payload noticeably smaller. A prefix is
+    /// compressed first so incompressible data is detected cheaply.
+    pub fn compress_if_beneficial(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if data.len() < MIN_COMPRESS_SIZE {
+            return Ok(None);
+        }
+        if data.len() > SAMPLE_SIZE && !self.compresses_well(&data[..SAMPLE_SIZE])? {
+            return Ok(None);
+        }
+        let compressed = self.compress(data)?;
+        Ok(Some(compressed).filter(|compressed| saves_enough(compressed.len(), data.len())))
+    }
+
+    /// Whether compressing `sample`, the start of a payload, makes it noticeably smaller.
+    pub fn compresses_well(&self, sample: &[u8]) -> io::Result<bool> {
+        Ok(saves_enough(self.compress(sample)?.len(), sample.len()))
+    }
+
+    /// Wrap a writer to compress what is written to it, for payloads too large to be
+    /// compressed in memory or produced bit by bit.
+    pub fn encoder<W: Write>(&self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, Compression::default())),
+            Encoding::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    /// Compress everything read from `reader` into `writer`. Return `writer` once the
+    /// compressed stream is complete.
+    pub fn compress_stream<R: Read, W: Write>(&self, reader: &mut R, writer: W) -> io::Result<W> {
+        let mut encoder = self.encoder(writer)?;
+        io::copy(reader, &mut encoder)?;
+        encoder.finish()
+    }
+
+    /// Wrap a reader of an encoded payload to read it decoded.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            // unlike `GzDecoder`, it reads to the end of `reader`, so that the trailer of
+            // a chunked payload is read too
+            Encoding::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Encoding::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    /// Pick the first encoding of the `accept-encoding` option known to this crate.
+    pub fn negotiate(accept_encoding: Option<&Value>) -> Option<Encoding> {
+        accept_encoding?
+            .as_array()?
+            .iter()
+            .filter_map(|encoding| encoding.as_str()?.parse().ok())
+            .next()
+    }
+}
+
+/// A writer compressing with an [`Encoding`], see [`Encoding::encoder`].
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Complete the compressed stream and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Compression is worth it when it saves at least a tenth of the size.
+fn saves_enough(compressed: usize, original: usize) -> bool {
+    compressed < original - original / 10
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            _ => Err(format!("Unknown encoding: {}", s)),
+        }
+    }
+}
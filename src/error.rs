@@ -0,0 +1,81 @@
+use crate::protocol::ErrorDetail;
+use crate::StatusCode;
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors of the client and server APIs.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to a socket or file failed.
+    Io(io::Error),
+    /// The peer sent something that does not follow the protocol.
+    Protocol(String),
+    /// The server answered with a status code other than `OK`, and why if it told.
+    Status(StatusCode, Option<ErrorDetail>),
+    /// The path cannot be requested, see [`sanitize_request_path`](crate::utils::sanitize_request_path).
+    InvalidPath(PathBuf),
+    /// The peer did not answer in time.
+    Timeout,
+    /// The server does not support what was asked, or no protocol version is supported
+    /// by both sides.
+    Unsupported(String),
+    /// The payload of a GET response ended with another number of bytes than its
+    /// length headers announced, e.g. because the connection was cut short.
+    Truncated { expected: u64, received: u64 },
+}
+
+/// `Result` with [`Error`] as the error type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Protocol(reason) => write!(f, "protocol violation: {}", reason),
+            Error::Status(status_code, None) => write!(f, "server answered {}", status_code),
+            Error::Status(status_code, Some(detail)) => {
+                write!(f, "server answered {}: {}", status_code, detail)
+            }
+            Error::InvalidPath(path) => write!(f, "invalid request path: {}", path.display()),
+            Error::Timeout => f.write_str("timed out"),
+            Error::Unsupported(reason) => write!(f, "not supported by the server: {}", reason),
+            Error::Truncated { expected, received } => write!(
+                f,
+                "received {} bytes of a file of {} bytes",
+                received, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Socket timeouts surface as `TimedOut` or `WouldBlock` depending on the platform,
+    /// both become [`Error::Timeout`]. An [`Error`] read from a payload is unwrapped.
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            let inner = err.into_inner().and_then(|inner| inner.downcast().ok());
+            return *inner.expect("checked to be an Error");
+        }
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Protocol(format!("malformed headers: {}", err))
+    }
+}
@@ -1,7 +1,20 @@
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod cache;
+pub mod client;
+pub mod encoding;
+pub mod error;
+pub mod logging;
+pub mod protocol;
 pub mod utils;
 
+pub use error::Error;
+
+use std::fmt;
+use std::str::FromStr;
+
 /// Type of status code of the server response.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StatusCode {
     OK,
     NotExist,
@@ -9,19 +22,38 @@ pub enum StatusCode {
     NotFile,
     ServerError,
     BadRequest,
+    NotModified,
 }
 
-impl ToString for StatusCode {
-    fn to_string(&self) -> String {
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use StatusCode::*;
-        match self {
+        f.write_str(match self {
             OK => "OK",
             NotExist => "NOT-EXIST",
             NotDirectory => "NOT-DIRECTORY",
             ServerError => "SERVER-ERROR",
             NotFile => "NOT-FILE",
             BadRequest => "BAD-REQUEST",
-        }
-        .into()
+            NotModified => "NOT-MODIFIED",
+        })
+    }
+}
+
+impl FromStr for StatusCode {
+    type Err = String;
+
+    /// Parse a status code as it appears on the status line, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match &s.to_uppercase()[..] {
+            "OK" => StatusCode::OK,
+            "NOT-EXIST" => StatusCode::NotExist,
+            "NOT-FILE" => StatusCode::NotFile,
+            "NOT-DIRECTORY" => StatusCode::NotDirectory,
+            "SERVER-ERROR" => StatusCode::ServerError,
+            "BAD-REQUEST" => StatusCode::BadRequest,
+            "NOT-MODIFIED" => StatusCode::NotModified,
+            _ => return Err(format!("Unknown status code: {}", s)),
+        })
     }
 }
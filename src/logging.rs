@@ -0,0 +1,138 @@
+//! Leveled logging for the server and client binaries.
+//!
+//! Log records go through the `log` facade, structured fields are attached with its
+//! key-value syntax, e.g. `info!(peer:% = addr, status:% = status; "request served")`.
+//! Records are written to stderr either as human-readable lines or as JSON lines.
+
+use crate::utils::UtcTime;
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use colored::*;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// Output format of log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `<timestamp> <level> <message> key=value ...`
+    Human,
+    /// One JSON object per line, fields are merged into the object.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format: {}", s)),
+        }
+    }
+}
+
+pub fn log_level_validator(s: String) -> Result<(), String> {
+    s.parse::<LevelFilter>()
+        .map(|_| ())
+        .map_err(|_| format!("Invalid log level: {}", s))
+}
+
+pub fn log_format_validator(s: String) -> Result<(), String> {
+    s.parse::<LogFormat>().map(|_| ())
+}
+
+/// Install the logger for the whole process, may only be called once.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger { format }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+struct Logger {
+    format: LogFormat,
+}
+
+/// Collects the key-value pairs attached to a record, in order.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+impl Logger {
+    fn human_line(&self, timestamp: &str, record: &Record, fields: &Fields) -> String {
+        let level = match record.level() {
+            Level::Error => "ERROR".red().bold(),
+            Level::Warn => " WARN".yellow().bold(),
+            Level::Info => " INFO".green(),
+            Level::Debug => "DEBUG".blue(),
+            Level::Trace => "TRACE".normal(),
+        };
+
+        let mut line = format!("{} {} {}", timestamp.dimmed(), level, record.args());
+        for (key, value) in &fields.0 {
+            match value {
+                serde_json::Value::String(s) if !s.contains(char::is_whitespace) => {
+                    let _ = write!(line, " {}={}", key.bold(), s);
+                }
+                _ => {
+                    let _ = write!(line, " {}={}", key.bold(), value);
+                }
+            }
+        }
+        line
+    }
+
+    fn json_line(&self, timestamp: &str, record: &Record, fields: &Fields) -> String {
+        let mut object = serde_json::Map::new();
+        object.insert("ts".into(), timestamp.into());
+        object.insert("level".into(), record.level().as_str().into());
+        object.insert("target".into(), record.target().into());
+        object.insert("msg".into(), record.args().to_string().into());
+        for (key, value) in &fields.0 {
+            object.insert(key.clone(), value.clone());
+        }
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = Fields(vec![]);
+        let _ = record.key_values().visit(&mut fields);
+        let timestamp = UtcTime::from(SystemTime::now()).to_rfc3339();
+        let line = match self.format {
+            LogFormat::Human => self.human_line(&timestamp, record, &fields),
+            LogFormat::Json => self.json_line(&timestamp, record, &fields),
+        };
+
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,727 @@
+//! Encoding and decoding of requests and response heads, shared by the client and the
+//! server. See the protocol specification in the README.
+//!
+//! The `encode`/`decode` functions work on bytes so they can be used with any I/O,
+//! including async streams. `write_to`/`read_from` are shortcuts for blocking I/O.
+//!
+//! ```
+//! use ssftp::protocol::{Method, Request, ResponseHead};
+//! use ssftp::StatusCode;
+//! use serde_json::json;
+//!
+//! let request = Request::new(Method::Get, "/baby.jpg");
+//! assert_eq!(request.encode().unwrap(), b"GET /baby.jpg\n");
+//!
+//! let mut wire: &[u8] = b"OK\n{\"content-length\":6}\nhello\n";
+//! let head = ResponseHead::read_from(&mut wire).unwrap();
+//! assert_eq!(head.status_code, StatusCode::OK);
+//! assert_eq!(head.headers, json!({ "content-length": 6 }));
+//! assert_eq!(wire, b"hello\n");
+//! ```
+
+use crate::error::{Error, Result};
+use crate::utils::sanitize_request_path;
+use crate::StatusCode;
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Map, Value};
+
+/// Newest protocol version implemented by this crate. Version 0 is the protocol
+/// without the `HELLO` method.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Oldest protocol version this crate can speak.
+pub const MIN_PROTOCOL_VERSION: u64 = 0;
+
+/// Method of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Info,
+    Dir,
+    /// Ask for the protocol version and capabilities of the server, the path is ignored.
+    Hello,
+    /// Search the subtree under the path.
+    Find,
+}
+
+impl Method {
+    /// Every method, in the order they were added to the protocol.
+    pub const ALL: [Method; 5] = [
+        Method::Get,
+        Method::Info,
+        Method::Dir,
+        Method::Hello,
+        Method::Find,
+    ];
+
+    /// The method as it appears on the request line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Info => "INFO",
+            Method::Dir => "DIR",
+            Method::Hello => "HELLO",
+            Method::Find => "FIND",
+        }
+    }
+
+    /// Protocol version which introduced the method.
+    pub fn since_version(&self) -> u64 {
+        match self {
+            Method::Get | Method::Info | Method::Dir => 0,
+            Method::Hello | Method::Find => 1,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    /// Parse a method as it appears on the request line, ignoring case.
+    fn from_str(s: &str) -> Result<Self> {
+        match &s.to_uppercase()[..] {
+            "GET" => Ok(Method::Get),
+            "INFO" => Ok(Method::Info),
+            "DIR" => Ok(Method::Dir),
+            "HELLO" => Ok(Method::Hello),
+            "FIND" => Ok(Method::Find),
+            _ => Err(Error::Protocol("bad method".into())),
+        }
+    }
+}
+
+/// Order of a paginated DIR listing, the `sort` request option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Name,
+    Size,
+    /// Modification time, oldest first.
+    Mtime,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Name => "name",
+            SortOrder::Size => "size",
+            SortOrder::Mtime => "mtime",
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(SortOrder::Name),
+            "size" => Ok(SortOrder::Size),
+            "mtime" => Ok(SortOrder::Mtime),
+            _ => Err(format!("Unknown sort order: {}", s)),
+        }
+    }
+}
+
+/// Marks a request line followed by a line of options, see [`Request`].
+const OPTIONS_PREFIX: &str = "SSFTP/1 ";
+
+/// Longest request or options line a server reads, `\n` included.
+pub const MAX_REQUEST_LINE: u64 = 64 * 1024;
+
+/// A request, `<method> <path>\n` on the wire. Requests with options are sent as
+/// `SSFTP/1 <method> <path>\n<options>\n` where the options are a JSON object, they
+/// can only be sent to servers speaking protocol version 1 or newer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub path: PathBuf,
+    pub options: Map<String, Value>,
+}
+
+impl Request {
+    pub fn new<P: Into<PathBuf>>(method: Method, path: P) -> Self {
+        Request {
+            method,
+            path: path.into(),
+            options: Map::new(),
+        }
+    }
+
+    /// Set the option `name`, e.g. `accept-encoding`.
+    pub fn with_option<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.options.insert(name.into(), value.into());
+        self
+    }
+
+    /// Encode the request, failing with [`Error::InvalidPath`] for paths which cannot
+    /// be requested.
+    ///
+    /// ```
+    /// use ssftp::protocol::{Method, Request};
+    ///
+    /// let request = Request::new(Method::Get, "/a.txt").with_option("accept-encoding", vec!["gzip"]);
+    /// assert_eq!(
+    ///     request.encode().unwrap(),
+    ///     b"SSFTP/1 GET /a.txt\n{\"accept-encoding\":[\"gzip\"]}\n"
+    /// );
+    /// ```
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        match self.path.to_str() {
+            Some(path) if sanitize_request_path(&self.path) => Ok(if self.options.is_empty() {
+                format!("{} {}\n", self.method, path)
+            } else {
+                let options = Value::Object(self.options.clone());
+                format!("{}{} {}\n{}\n", OPTIONS_PREFIX, self.method, path, options)
+            }
+            .into_bytes()),
+            _ => Err(Error::InvalidPath(self.path.clone())),
+        }
+    }
+
+    /// Whether the request line is followed by a line of options.
+    pub fn has_options_line(request_line: &[u8]) -> bool {
+        request_line.starts_with(OPTIONS_PREFIX.as_bytes())
+    }
+
+    /// Decode a request line, and the options line if
+    /// [`has_options_line`](Request::has_options_line) tells there is one, both with or
+    /// without their trailing newline. The path is not checked, the server decides what
+    /// to do with it. The error is the detail of the `BAD-REQUEST` response to send back.
+    ///
+    /// ```
+    /// use ssftp::protocol::{Method, Request};
+    ///
+    /// let request = Request::decode(b"dir /cute dogs\n", None).unwrap();
+    /// assert_eq!(request, Request::new(Method::Dir, "/cute dogs"));
+    /// assert_eq!(Request::decode(b"PUT /a.txt\n", None).unwrap_err().reason, "bad-method");
+    /// assert_eq!(Request::decode(b"GET\n", None).unwrap_err().reason, "bad-request");
+    ///
+    /// let request = Request::decode(b"SSFTP/1 GET /a.txt\n", Some(b"{\"x\":1}\n")).unwrap();
+    /// assert_eq!(request, Request::new(Method::Get, "/a.txt").with_option("x", 1));
+    /// ```
+    pub fn decode(
+        request_line: &[u8],
+        options_line: Option<&[u8]>,
+    ) -> std::result::Result<Request, ErrorDetail> {
+        let line = request_line.strip_suffix(b"\n").unwrap_or(request_line);
+        let line = String::from_utf8_lossy(line);
+        let line = line.strip_prefix(OPTIONS_PREFIX).unwrap_or(&line);
+
+        let mut iterator = line.splitn(2, ' ');
+        let mut request = match (iterator.next(), iterator.next()) {
+            (Some(method), Some(path)) => match method.parse() {
+                Ok(method) => Request::new(method, path),
+                Err(_) => {
+                    return Err(ErrorDetail::new(
+                        "bad-method",
+                        format!("unknown method {:?}", method),
+                    ))
+                }
+            },
+            _ => {
+                return Err(ErrorDetail::new(
+                    "bad-request",
+                    "expected a request line \"<method> <path>\"",
+                ))
+            }
+        };
+
+        if Request::has_options_line(request_line) {
+            let options = options_line.map(serde_json::from_slice::<Value>);
+            match options {
+                Some(Ok(Value::Object(options))) => request.options = options,
+                _ => {
+                    return Err(ErrorDetail::bad_options(
+                        "expected a JSON object on the line after the request line",
+                    ))
+                }
+            }
+        }
+        Ok(request)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.encode()?)?;
+        Ok(())
+    }
+
+    /// Read and decode a request line, and its options line if any.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request> {
+        let mut request_line = vec![];
+        reader.read_until(b'\n', &mut request_line)?;
+        let mut options_line = vec![];
+        if Request::has_options_line(&request_line) {
+            reader.read_until(b'\n', &mut options_line)?;
+        }
+        Request::decode(&request_line, Some(&options_line))
+            .map_err(|detail| Error::Protocol(detail.message))
+    }
+}
+
+/// The status line and headers of a response, followed by the payload on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseHead {
+    pub status_code: StatusCode,
+    pub headers: Value,
+}
+
+impl ResponseHead {
+    pub fn new(status_code: StatusCode, headers: Value) -> Self {
+        ResponseHead {
+            status_code,
+            headers,
+        }
+    }
+
+    /// The head of a response other than `OK`, the headers carry the detail.
+    pub fn error(status_code: StatusCode, detail: &ErrorDetail) -> Self {
+        ResponseHead::new(status_code, detail.to_headers())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{}\n{}\n", self.status_code, self.headers).into_bytes()
+    }
+
+    /// Decode the status line and headers line, with or without their trailing newlines.
+    pub fn decode(status_line: &[u8], headers_line: &[u8]) -> Result<ResponseHead> {
+        Ok(ResponseHead::new(
+            decode_status_line(status_line)?,
+            decode_headers_line(headers_line)?,
+        ))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.encode())?;
+        Ok(())
+    }
+
+    /// Read and decode the status line and headers, leaving the payload in `reader`.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<ResponseHead> {
+        let mut status_line = vec![];
+        reader.read_until(b'\n', &mut status_line)?;
+        let status_code = decode_status_line(&status_line)?;
+
+        let mut headers_line = vec![];
+        reader.read_until(b'\n', &mut headers_line)?;
+        Ok(ResponseHead::new(
+            status_code,
+            decode_headers_line(&headers_line)?,
+        ))
+    }
+}
+
+/// Why the server answered with a status code other than `OK`, sent in the `reason`
+/// and `message` headers.
+///
+/// ```
+/// use ssftp::protocol::ErrorDetail;
+/// use serde_json::json;
+///
+/// let detail = ErrorDetail::new("parent-path", "the path must not contain \"..\"");
+/// let headers = detail.to_headers();
+/// assert_eq!(headers["reason"], json!("parent-path"));
+/// assert_eq!(ErrorDetail::from_headers(&headers), Some(detail));
+/// assert_eq!(ErrorDetail::from_headers(&json!({})), None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorDetail {
+    /// Machine-readable kebab-case identifier, e.g. `bad-method` or `parent-path`.
+    pub reason: String,
+    /// Human readable explanation.
+    pub message: String,
+}
+
+impl ErrorDetail {
+    pub fn new<S: Into<String>>(reason: &str, message: S) -> Self {
+        ErrorDetail {
+            reason: reason.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Detail of a request whose options line is not a JSON object, or has an option
+    /// with an invalid value.
+    pub fn bad_options<S: Into<String>>(message: S) -> Self {
+        ErrorDetail::new("bad-options", message)
+    }
+
+    pub fn to_headers(&self) -> Value {
+        json!({ "reason": self.reason, "message": self.message })
+    }
+
+    /// Read the detail from the headers of a response, `None` if there is no `reason`
+    /// as with servers predating it.
+    pub fn from_headers(headers: &Value) -> Option<ErrorDetail> {
+        let reason = headers.get("reason")?.as_str()?;
+        let message = headers.get("message").and_then(Value::as_str);
+        Some(ErrorDetail::new(reason, message.unwrap_or_default()))
+    }
+}
+
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.reason)
+    }
+}
+
+/// Decode the status line of a response.
+///
+/// ```
+/// use ssftp::protocol::decode_status_line;
+/// use ssftp::StatusCode;
+///
+/// assert_eq!(decode_status_line(b"NOT-EXIST\n").unwrap(), StatusCode::NotExist);
+/// assert!(decode_status_line(b"").is_err());
+/// ```
+pub fn decode_status_line(line: &[u8]) -> Result<StatusCode> {
+    if line.is_empty() {
+        return Err(Error::Protocol(
+            "connection closed before the status line".into(),
+        ));
+    }
+    String::from_utf8_lossy(line)
+        .trim_end()
+        .parse()
+        .map_err(Error::Protocol)
+}
+
+/// Decode the headers line of a response, a JSON object.
+pub fn decode_headers_line(line: &[u8]) -> Result<Value> {
+    match serde_json::from_slice(line)? {
+        Value::Object(headers) => Ok(Value::Object(headers)),
+        _ => Err(Error::Protocol("headers are not a JSON object".into())),
+    }
+}
+
+/// Largest chunk written by [`ChunkedWriter`].
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Trailer headers of a chunked payload, shared with the [`ChunkedReader`] reading it.
+/// They are only known once the payload is read to the end.
+#[derive(Debug, Clone, Default)]
+pub struct Trailer(Arc<Mutex<Option<Map<String, Value>>>>);
+
+impl Trailer {
+    /// The trailer headers, `None` until the terminating chunk is read.
+    pub fn get(&self) -> Option<Map<String, Value>> {
+        self.0.lock().ok().and_then(|trailer| trailer.clone())
+    }
+
+    fn set(&self, headers: Map<String, Value>) {
+        if let Ok(mut trailer) = self.0.lock() {
+            *trailer = Some(headers);
+        }
+    }
+}
+
+/// Write a payload with chunked framing: each chunk is its length in decimal on its own
+/// line followed by its bytes, and a zero chunk followed by the trailer headers line
+/// terminates the payload.
+///
+/// ```
+/// use ssftp::protocol::{ChunkedReader, ChunkedWriter};
+/// use serde_json::json;
+/// use std::io::{Read, Write};
+///
+/// let mut writer = ChunkedWriter::new(vec![]);
+/// writer.write_all(b"hello ").unwrap();
+/// writer.flush().unwrap();
+/// writer.write_all(b"world").unwrap();
+/// let wire = writer.finish(&json!({ "count": 2 })).unwrap();
+/// assert_eq!(wire, b"6\nhello 5\nworld0\n{\"count\":2}\n");
+///
+/// let mut reader = ChunkedReader::new(&wire[..]);
+/// let mut payload = String::new();
+/// reader.read_to_string(&mut payload).unwrap();
+/// assert_eq!(payload, "hello world");
+/// assert_eq!(reader.trailer().get().unwrap()["count"], 2);
+/// ```
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter {
+            inner,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            writeln!(self.inner, "{}", self.buffer.len())?;
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Write the pending chunk, the terminating chunk and the trailer headers, which
+    /// must be a JSON object. Return the inner writer, not flushed.
+    pub fn finish(mut self, trailer: &Value) -> io::Result<W> {
+        self.write_chunk()?;
+        self.inner.write_all(b"0\n")?;
+        serde_json::to_writer(&mut self.inner, trailer)?;
+        self.inner.write_all(b"\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(written)
+    }
+
+    /// Write the pending bytes as a chunk, so the reader gets them now.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+/// Read a payload written by [`ChunkedWriter`], without the framing. Reading fails with
+/// `UnexpectedEof` if the connection ends before the terminating chunk.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+    trailer: Trailer,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+            trailer: Trailer::default(),
+        }
+    }
+
+    /// The trailer headers, filled in once the payload is read to the end.
+    pub fn trailer(&self) -> Trailer {
+        self.trailer.clone()
+    }
+
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = vec![];
+        self.inner.read_until(b'\n', &mut line)?;
+        match line.pop() {
+            Some(b'\n') => Ok(line),
+            _ => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            self.remaining = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .ok_or_else(|| invalid_data("malformed chunk length"))?;
+            if self.remaining == 0 {
+                match decode_headers_line(&self.read_line()?) {
+                    Ok(Value::Object(trailer)) => self.trailer.set(trailer),
+                    _ => return Err(invalid_data("malformed trailer headers")),
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn read_chunked(wire: &[u8]) -> (io::Result<Vec<u8>>, Trailer) {
+        let mut reader = ChunkedReader::new(wire);
+        let mut payload = vec![];
+        let result = reader.read_to_end(&mut payload).map(|_| payload);
+        (result, reader.trailer())
+    }
+
+    fn error_kind(wire: &[u8]) -> io::ErrorKind {
+        read_chunked(wire).0.unwrap_err().kind()
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let requests = [
+            Request::new(Method::Info, "/a dir/b.txt"),
+            Request::new(Method::Dir, "/")
+                .with_option("sort", "size")
+                .with_option("limit", 10),
+        ];
+        for request in requests {
+            let wire = request.encode().unwrap();
+            let decoded = Request::read_from(&mut &wire[..]).unwrap();
+            assert_eq!(decoded, request);
+        }
+    }
+
+    #[test]
+    fn request_with_invalid_path_is_not_encoded() {
+        let request = Request::new(Method::Get, "/a/../b");
+        assert!(matches!(request.encode(), Err(Error::InvalidPath(_))));
+    }
+
+    #[test]
+    fn malformed_options_line() {
+        let request_line = b"SSFTP/1 GET /a.txt\n";
+        let options_lines: [&[u8]; 4] = [b"{\"x\":\n", b"[1, 2]\n", b"\n", b"not json\n"];
+        for options_line in options_lines {
+            let detail = Request::decode(request_line, Some(options_line)).unwrap_err();
+            assert_eq!(detail.reason, "bad-options");
+        }
+        let detail = Request::decode(request_line, None).unwrap_err();
+        assert_eq!(detail.reason, "bad-options");
+
+        // the connection ends before the options line
+        let result = Request::read_from(&mut &request_line[..]);
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn response_head_round_trip() {
+        let heads = [
+            ResponseHead::new(StatusCode::OK, json!({ "content-length": 5 })),
+            ResponseHead::error(
+                StatusCode::NotExist,
+                &ErrorDetail::new("not-exist", "no such file"),
+            ),
+        ];
+        for head in heads {
+            let mut wire = head.encode();
+            wire.extend_from_slice(b"hello");
+            let mut reader = &wire[..];
+            assert_eq!(ResponseHead::read_from(&mut reader).unwrap(), head);
+            // the payload is left to read
+            assert_eq!(reader, b"hello");
+        }
+    }
+
+    #[test]
+    fn response_head_eof_before_headers_line() {
+        let wires: [&[u8]; 3] = [b"", b"OK\n", b"OK\n{\"content-length\""];
+        for wire in wires {
+            let result = ResponseHead::read_from(&mut &wire[..]);
+            assert!(matches!(result, Err(Error::Protocol(_))), "{:?}", wire);
+        }
+    }
+
+    #[test]
+    fn response_head_with_malformed_lines() {
+        let result = ResponseHead::decode(b"MAYBE\n", b"{}\n");
+        assert!(matches!(result, Err(Error::Protocol(_))));
+        let result = ResponseHead::decode(b"OK\n", b"[\"content-length\"]\n");
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let payload: Vec<u8> = (0..3 * CHUNK_SIZE + 7).map(|i| i as u8).collect();
+        let mut writer = ChunkedWriter::new(vec![]);
+        writer.write_all(&payload).unwrap();
+        let wire = writer.finish(&json!({ "count": 3 })).unwrap();
+
+        let (result, trailer) = read_chunked(&wire);
+        assert_eq!(result.unwrap(), payload);
+        assert_eq!(trailer.get().unwrap()["count"], 3);
+    }
+
+    #[test]
+    fn chunked_empty_payload() {
+        let wire = ChunkedWriter::new(vec![]).finish(&json!({})).unwrap();
+        assert_eq!(wire, b"0\n{}\n");
+        let (result, trailer) = read_chunked(&wire);
+        assert!(result.unwrap().is_empty());
+        assert_eq!(trailer.get(), Some(Map::new()));
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        // the connection ends in the middle of a chunk
+        assert_eq!(error_kind(b"10\nhello"), io::ErrorKind::UnexpectedEof);
+        // or before the terminating chunk
+        assert_eq!(error_kind(b"5\nhello"), io::ErrorKind::UnexpectedEof);
+        // or in the middle of a length line
+        assert_eq!(error_kind(b"5\nhello1"), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_chunk_length() {
+        let wires: [&[u8]; 5] = [
+            b"abc\nhello0\n{}\n",
+            b"-5\nhello0\n{}\n",
+            b"0x5\nhello0\n{}\n",
+            b"\nhello0\n{}\n",
+            b"99999999999999999999999\nhello0\n{}\n",
+        ];
+        for wire in wires {
+            assert_eq!(error_kind(wire), io::ErrorKind::InvalidData, "{:?}", wire);
+        }
+    }
+
+    #[test]
+    fn missing_or_invalid_trailer() {
+        let (result, trailer) = read_chunked(b"5\nhello0\n");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(trailer.get(), None);
+
+        let wires: [&[u8]; 3] = [
+            b"5\nhello0\n\n",
+            b"5\nhello0\nnot json\n",
+            b"5\nhello0\n[1]\n",
+        ];
+        for wire in wires {
+            let (result, trailer) = read_chunked(wire);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(trailer.get(), None);
+        }
+    }
+}
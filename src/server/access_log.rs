@@ -0,0 +1,111 @@
+use ssftp::utils::UtcTime;
+use ssftp::StatusCode;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// One completed (or aborted) request, as written to the access log.
+pub struct AccessEntry<'a> {
+    pub peer: SocketAddr,
+    pub start: SystemTime,
+    /// `None` when the request could not be parsed.
+    pub request: Option<(&'a str, &'a Path)>,
+    pub status: StatusCode,
+    pub bytes_sent: u64,
+    pub duration: Duration,
+    pub aborted: bool,
+}
+
+impl AccessEntry<'_> {
+    /// Format the entry similar to the Common Log Format, followed by the duration in
+    /// milliseconds and whether the transfer completed, e.g.
+    /// `10.1.2.3 - - [18/Oct/2021:14:38:10 +0000] "GET /a.txt" OK 1052 3.127 done`
+    fn to_line(&self) -> String {
+        let request = match self.request {
+            Some((method, path)) => format!("{} {}", method, path.to_string_lossy().escape_debug()),
+            None => "-".into(),
+        };
+
+        format!(
+            "{} - - [{}] \"{}\" {} {} {:.3} {}\n",
+            self.peer.ip(),
+            UtcTime::from(self.start).to_clf(),
+            request,
+            self.status,
+            self.bytes_sent,
+            self.duration.as_secs_f64() * 1000.0,
+            if self.aborted { "aborted" } else { "done" },
+        )
+    }
+}
+
+/// Append-only access log file, rotated when it grows beyond a size limit.
+///
+/// On rotation `access.log` is renamed to `access.log.1`, `access.log.1` to
+/// `access.log.2` and so on, keeping at most `keep` old files.
+pub struct AccessLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AccessLog {
+            path: PathBuf::from(path),
+            max_size,
+            keep,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    /// Append an entry, rotating the file first if it would exceed the size limit.
+    pub fn record(&self, entry: &AccessEntry) -> io::Result<()> {
+        let line = entry.to_line();
+        let mut guard = self
+            .file
+            .lock()
+            .map_err(|_| io::Error::other("access log lock poisoned"))?;
+
+        if guard.1 > 0 && guard.1 + line.len() as u64 > self.max_size {
+            guard.0 = self.rotate()?;
+            guard.1 = 0;
+        }
+
+        guard.0.write_all(line.as_bytes())?;
+        guard.1 += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
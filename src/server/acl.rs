@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Allow and deny rules for the address of incoming connections.
+///
+/// A connection is rejected if its address matches any deny rule. If at least one
+/// allow rule is given, the address must also match one of them.
+#[derive(Debug, Default, Clone)]
+pub struct AccessRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+/// Parse a rule in CIDR notation, a bare IP address is taken as a single host.
+fn parse_rule(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid CIDR range: {}", s))
+}
+
+pub fn cidr_validator(s: String) -> Result<(), String> {
+    parse_rule(&s).map(|_| ())
+}
+
+impl AccessRules {
+    /// Build the rules from lists of CIDR ranges, e.g. `10.0.0.0/8` or `fd00::/8`.
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<Self, String> {
+        let parse_all = |rules: &[S]| {
+            rules
+                .iter()
+                .map(|rule| parse_rule(rule.as_ref()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(AccessRules {
+            allow: parse_all(allow)?,
+            deny: parse_all(deny)?,
+        })
+    }
+
+    /// Check whether a connection from `addr` should be served.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        // peers of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+        let matches = |net: &IpNet| net.contains(&addr);
+
+        if self.deny.iter().any(matches) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(matches)
+    }
+}
@@ -0,0 +1,158 @@
+use crate::connections::ActiveConnections;
+use crate::server::{Reloader, ServerConfig};
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{info, warn, LevelFilter};
+
+const HELP: &str = "\
+commands:
+  list                 list active connections
+  kill <id>            close the connection with the given id
+  log-level [level]    show or change the log level
+  reload               reload the configuration
+  help                 show this message";
+
+/// What the admin commands are allowed to inspect and change.
+pub struct AdminContext {
+    pub connections: Arc<ActiveConnections>,
+    pub config: Arc<Mutex<ServerConfig>>,
+    pub reloader: Option<Arc<Reloader>>,
+}
+
+impl AdminContext {
+    /// Run one command line, return the reply or an error message.
+    fn execute(&self, command: &str) -> Result<String, String> {
+        let mut words = command.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("list"), None) => Ok(self.list()),
+
+            (Some("kill"), Some(id)) => {
+                let id: u64 = id.parse().map_err(|_| format!("invalid id: {}", id))?;
+                if self.connections.kill(id) {
+                    Ok(format!("killed connection {}", id))
+                } else {
+                    Err(format!("no connection with id {}", id))
+                }
+            }
+
+            (Some("log-level"), None) => Ok(format!("log level is {}", log::max_level())),
+            (Some("log-level"), Some(level)) => {
+                let level: LevelFilter = level
+                    .parse()
+                    .map_err(|_| format!("invalid log level: {}", level))?;
+                log::set_max_level(level);
+                info!(level:% = level; "log level changed from admin socket");
+                Ok(format!("log level set to {}", level))
+            }
+
+            (Some("reload"), None) => {
+                let reloader = self
+                    .reloader
+                    .as_ref()
+                    .ok_or("reloading is not supported by this server")?;
+                let new_config = reloader().map_err(|err| format!("reload failed: {}", err))?;
+                let mut config = self.config.lock().map_err(|_| "config lock poisoned")?;
+                *config = new_config;
+                info!("configuration reloaded from admin socket");
+                Ok("configuration reloaded".into())
+            }
+
+            (Some("help"), None) => Ok(HELP.into()),
+            _ => Err(format!("unknown command: {}\n{}", command.trim(), HELP)),
+        }
+    }
+
+    fn list(&self) -> String {
+        let mut lines = vec![format!(
+            "{:>6}  {:<40}  {:<6}  {:>12}  {:>9}  PATH",
+            "ID", "PEER", "METHOD", "BYTES", "SECONDS"
+        )];
+        for connection in self.connections.list() {
+            lines.push(format!(
+                "{:>6}  {:<40}  {:<6}  {:>12}  {:>9.3}  {}",
+                connection.id,
+                connection.peer,
+                connection.method.unwrap_or("-"),
+                connection.bytes_sent,
+                connection.elapsed.as_secs_f64(),
+                connection
+                    .path
+                    .map_or("-".into(), |path| path.display().to_string()),
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Read one command from the admin client and write back the reply. Replies start
+/// with `OK` or `ERROR` on their own line.
+fn handle_admin(stream: UnixStream, context: &AdminContext) -> io::Result<()> {
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+
+    let reply = match context.execute(&command) {
+        Ok(reply) => format!("OK\n{}\n", reply),
+        Err(err) => {
+            warn!(command = command.trim(); "admin command failed: {}", err);
+            format!("ERROR\n{}\n", err)
+        }
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+/// Bind a Unix socket at `path` which no other user can ever connect to. It is bound
+/// in a fresh directory only the server user can enter, restricted, then moved in place.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "socket path has no file name")
+    })?;
+    let mut dir_name = name.to_os_string();
+    dir_name.push(format!(".{}.tmp", process::id()));
+    let dir = path.with_file_name(dir_name);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let temp_path = dir.join("socket");
+    let bound = UnixListener::bind(&temp_path).and_then(|listener| {
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&temp_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&temp_path);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+/// Listen on the Unix socket at `path` from a background thread. The socket is only
+/// accessible by the user running the server.
+pub fn serve(path: &Path, context: AdminContext) -> io::Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ));
+        }
+        // left over from a previous run
+        fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    info!(path:% = path.display(); "admin socket started");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_admin(stream, &context));
+            if let Err(err) = result {
+                warn!("error occured while serving admin socket: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,381 @@
+//! Serve connections as tasks on a tokio runtime instead of the thread pool, so that
+//! a slow client does not hold a thread for the whole transfer. The wire protocol and
+//! the request handling are shared with the thread pool server.
+
+use crate::conditional::with_validators;
+use crate::connections::ActiveConnections;
+use crate::find::find_payload;
+use crate::listing::page_payload;
+use crate::metrics::Metrics;
+use crate::server::{
+    check_line_length, chunked_headers, compressed_file_headers, dir_payload, encode_payload,
+    file_changed_detail, file_changed_error, file_headers, info_headers, is_same_version, prepare,
+    sample_file, write_chunked, write_chunked_listing, write_compressed_file, Connection,
+    CountingWriter, Framing, PendingResponse, SSFTPServer, ServerConfig, MAX_COMPRESS_SIZE,
+};
+use ssftp::encoding::SAMPLE_SIZE;
+use ssftp::protocol::{ErrorDetail, Request, ResponseHead, MAX_REQUEST_LINE};
+use ssftp::StatusCode;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use log::error;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task;
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.count.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl SSFTPServer {
+    /// Block the program and start listening, serving connections on a tokio runtime
+    /// with as many worker threads as the thread pool would have.
+    pub fn start_async(&self) -> ssftp::error::Result<()> {
+        // the thread pool only sets the number of runtime workers, it serves nothing
+        self.start_side_listeners(None);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.thread_pool.max_count())
+            .enable_io()
+            .build()?;
+        runtime.block_on(self.accept_loop())?;
+        Ok(())
+    }
+
+    async fn accept_loop(&self) -> io::Result<()> {
+        let listener = self.listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+
+        loop {
+            let (stream, peer_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("error occured while accepting a stream: {}", err);
+                    continue;
+                }
+            };
+
+            // keep a std handle of the socket in the registry so it can be killed
+            let stream = match stream.into_std() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!(peer:% = peer_addr; "cannot take over accepted stream: {}", err);
+                    continue;
+                }
+            };
+            let connection = match self.accept(peer_addr, stream.try_clone()) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            let stream = match TcpStream::from_std(stream) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!(peer:% = peer_addr; "cannot register accepted stream: {}", err);
+                    self.connections.remove(connection.id);
+                    self.metrics.connection_closed();
+                    continue;
+                }
+            };
+
+            let config = Arc::clone(&self.config);
+            let metrics = Arc::clone(&self.metrics);
+            let connections = Arc::clone(&self.connections);
+            tokio::spawn(async move {
+                handle_client(config, stream, &connection, &metrics, &connections).await;
+                connections.remove(connection.id);
+                metrics.connection_closed();
+            });
+        }
+    }
+}
+
+async fn handle_client(
+    config: Arc<Mutex<ServerConfig>>,
+    stream: TcpStream,
+    connection: &Connection,
+    metrics: &Metrics,
+    connections: &ActiveConnections,
+) {
+    let access_log = match config.lock() {
+        Ok(config) => config.access_log.clone(),
+        Err(_) => return,
+    };
+
+    let mut reader = BufReader::new(stream);
+    let mut raw_request = vec![];
+    let mut raw_options = vec![];
+    let read = match read_line(&mut reader, &mut raw_request).await {
+        Ok(()) if Request::has_options_line(&raw_request) => {
+            read_line(&mut reader, &mut raw_options).await
+        }
+        read => read,
+    };
+    let request = match read {
+        Ok(()) => Request::decode(&raw_request, Some(&raw_options)),
+        Err(err) => Err(ErrorDetail::new(
+            "bad-request",
+            format!("cannot read the request: {}", err),
+        )),
+    };
+    if let Ok(request) = &request {
+        connections.set_request(connection.id, request.method.as_str(), &request.path);
+    }
+
+    // preparing touches the file system, keep it off the runtime threads
+    let response = {
+        let (config, request, connection) = (config, request.clone(), connection.clone());
+        task::spawn_blocking(move || prepare(&config, &request, &connection))
+            .await
+            .unwrap_or_else(|_| {
+                PendingResponse::error(StatusCode::ServerError, "server-error", "internal error")
+            })
+    };
+
+    let status = response.status_code();
+    let bytes_sent = Arc::clone(&connection.bytes_sent);
+    let mut writer = CountingWriter::new(reader.into_inner(), bytes_sent);
+    let result = perform_response(&mut writer, response).await;
+    if let Err(err) = &result {
+        error!(
+            peer:% = connection.peer, rid = connection.id;
+            "error occured while handling a client: {}", err
+        );
+    }
+    connection.finish(&request, status, result.is_err(), access_log, metrics);
+}
+
+/// Async counterpart of `perform_response`, writing exactly the same bytes.
+/// Read a request or options line of at most [`MAX_REQUEST_LINE`] bytes.
+async fn read_line(reader: &mut BufReader<TcpStream>, line: &mut Vec<u8>) -> io::Result<()> {
+    reader
+        .take(MAX_REQUEST_LINE)
+        .read_until(b'\n', line)
+        .await?;
+    check_line_length(line)
+}
+
+async fn perform_response(
+    writer: &mut CountingWriter<TcpStream>,
+    response: PendingResponse,
+) -> io::Result<()> {
+    let status_code = response.status_code();
+    match response {
+        PendingResponse::Get(
+            mut file,
+            Framing {
+                encoding: Some(encoding),
+                chunked: true,
+            },
+        ) if file.metadata()?.len() > SAMPLE_SIZE as u64 => {
+            let metadata = file.metadata()?;
+            let (mut file, sample) = task::spawn_blocking(move || {
+                let sample = sample_file(&mut file, encoding)?;
+                Ok::<_, io::Error>((file, sample))
+            })
+            .await??;
+            match sample {
+                Some(sample) => {
+                    let headers = compressed_file_headers(&metadata, encoding);
+                    write_head(writer, status_code, headers).await?;
+                    forward_blocking(writer, move |channel| {
+                        write_compressed_file(&mut file, &metadata, &sample, encoding, channel)?
+                            .flush()
+                    })
+                    .await?;
+                }
+                None => send_file(writer, file).await?,
+            }
+        }
+
+        PendingResponse::Get(
+            mut file,
+            Framing {
+                encoding: Some(encoding),
+                ..
+            },
+        ) if file.metadata()?.len() <= MAX_COMPRESS_SIZE => {
+            let metadata = file.metadata()?;
+            let content_length = metadata.len();
+            let encoded = task::spawn_blocking(move || {
+                let mut content = vec![];
+                (&mut file).take(content_length).read_to_end(&mut content)?;
+                let unchanged = file
+                    .metadata()
+                    .is_ok_and(|current| is_same_version(&current, &metadata));
+                if content.len() as u64 != content_length || !unchanged {
+                    return Ok(None);
+                }
+                encode_payload(content, Some(encoding)).map(|encoded| Some((encoded, metadata)))
+            })
+            .await??;
+            let ((headers, payload), metadata) = match encoded {
+                Some(encoded) => encoded,
+                None => {
+                    let headers = file_changed_detail().to_headers();
+                    write_head(writer, StatusCode::ServerError, headers).await?;
+                    writer.flush().await?;
+                    return Err(file_changed_error());
+                }
+            };
+            let headers = file_headers(headers, &metadata);
+            write_head(writer, status_code, headers).await?;
+            writer.write_all(&payload).await?;
+        }
+
+        PendingResponse::Get(file, _) => send_file(writer, file).await?,
+
+        PendingResponse::Info(metadata) => {
+            write_head(writer, status_code, info_headers(&metadata)).await?;
+        }
+
+        PendingResponse::NotModified(metadata) => {
+            write_head(writer, status_code, with_validators(json!({}), &metadata)).await?;
+        }
+
+        PendingResponse::Dir(entries, framing, Some(page)) => {
+            let (headers, payload) =
+                task::spawn_blocking(move || page_payload(entries, &page, framing.encoding))
+                    .await??;
+            write_head(writer, status_code, headers).await?;
+            writer.write_all(&payload).await?;
+        }
+
+        PendingResponse::Dir(entries, framing, None) if framing.chunked => {
+            write_head(writer, status_code, chunked_headers(framing.encoding)).await?;
+            forward_blocking(writer, move |channel| {
+                write_chunked_listing(entries, framing.encoding, channel)?.flush()
+            })
+            .await?;
+        }
+
+        PendingResponse::Dir(entries, framing, None) => {
+            let (headers, payload) =
+                task::spawn_blocking(move || dir_payload(entries, framing.encoding)).await??;
+            write_head(writer, status_code, headers).await?;
+            writer.write_all(&payload).await?;
+        }
+
+        PendingResponse::Find(entries, query, framing) if framing.chunked => {
+            write_head(writer, status_code, chunked_headers(framing.encoding)).await?;
+            forward_blocking(writer, move |channel| {
+                write_chunked(framing.encoding, channel, |writer| {
+                    query.write_results(entries, writer).map(Value::Object)
+                })?
+                .flush()
+            })
+            .await?;
+        }
+
+        PendingResponse::Find(entries, query, framing) => {
+            let (headers, payload) =
+                task::spawn_blocking(move || find_payload(entries, &query, framing.encoding))
+                    .await??;
+            write_head(writer, status_code, headers).await?;
+            writer.write_all(&payload).await?;
+        }
+
+        PendingResponse::Hello(headers) => {
+            write_head(writer, status_code, headers).await?;
+        }
+
+        PendingResponse::Error(_, detail) => {
+            write_head(writer, status_code, detail.to_headers()).await?;
+        }
+    }
+    writer.flush().await
+}
+
+/// Send a file as is, the head of an `OK` response then its content. Like the thread
+/// pool server, hold the last byte back until the file is checked.
+async fn send_file(writer: &mut CountingWriter<TcpStream>, file: File) -> io::Result<()> {
+    let metadata = file.metadata()?;
+    let content_length = metadata.len();
+    let headers = file_headers(json!({ "content-length": content_length }), &metadata);
+    write_head(writer, StatusCode::OK, headers).await?;
+    let body_length = content_length.saturating_sub(1);
+    let mut file = tokio::fs::File::from_std(file);
+    let sent = tokio::io::copy(&mut (&mut file).take(body_length), writer).await?;
+    let unchanged = file
+        .metadata()
+        .await
+        .is_ok_and(|current| is_same_version(&current, &metadata));
+    if sent != body_length || !unchanged {
+        return Err(file_changed_error());
+    }
+    let last = tokio::io::copy(&mut file.take(content_length - body_length), writer).await?;
+    if last != content_length - body_length {
+        return Err(file_changed_error());
+    }
+    Ok(())
+}
+
+/// Number of buffers of a streamed payload waiting to be sent, before reading the
+/// directory or file pauses.
+const PAYLOAD_CHANNEL_SIZE: usize = 4;
+
+/// Run `produce` on a blocking thread, since it reads directories or files, and forward
+/// what it writes to the connection as it is produced.
+async fn forward_blocking<F>(writer: &mut CountingWriter<TcpStream>, produce: F) -> io::Result<()>
+where
+    F: FnOnce(BufWriter<ChannelWriter>) -> io::Result<()> + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel(PAYLOAD_CHANNEL_SIZE);
+    let producer = task::spawn_blocking(move || produce(BufWriter::new(ChannelWriter(sender))));
+    while let Some(bytes) = receiver.recv().await {
+        writer.write_all(&bytes).await?;
+    }
+    producer.await?
+}
+
+/// Send what is written, from a blocking thread, to the task writing the response.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.blocking_send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            // the task gave up on the connection
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn write_head(
+    writer: &mut CountingWriter<TcpStream>,
+    status_code: StatusCode,
+    headers: serde_json::Value,
+) -> io::Result<()> {
+    let head = ResponseHead::new(status_code, headers);
+    writer.write_all(&head.encode()).await
+}
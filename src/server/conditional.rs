@@ -0,0 +1,90 @@
+//! Conditional GET: files are sent with validators, an `etag` and a `last-modified`
+//! time, and a client which already has a copy sends them back as `if-none-match` and
+//! `if-modified-since` options to be answered `NOT-MODIFIED` without a payload when the
+//! file did not change.
+
+use ssftp::protocol::ErrorDetail;
+
+use std::fs::Metadata;
+use std::time::UNIX_EPOCH;
+
+use serde_json::{json, Map, Value};
+
+/// Tag of the current version of a file, from its inode, size and modification time.
+/// Clients must treat it as an opaque string.
+pub(crate) fn etag(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_nanos());
+    format!("{:x}-{:x}-{:x}", inode(metadata), metadata.len(), mtime)
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Modification time in seconds since the Unix epoch.
+fn last_modified(metadata: &Metadata) -> Option<u64> {
+    let mtime = metadata.modified().ok()?;
+    Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// The `etag` and `last-modified` headers of a file.
+fn validator_headers(metadata: &Metadata) -> Map<String, Value> {
+    let mut headers = Map::new();
+    headers.insert("etag".into(), json!(etag(metadata)));
+    if let Some(last_modified) = last_modified(metadata) {
+        headers.insert("last-modified".into(), json!(last_modified));
+    }
+    headers
+}
+
+/// Add the validators of a file to the headers of a response.
+pub(crate) fn with_validators(mut headers: Value, metadata: &Metadata) -> Value {
+    if let Value::Object(headers) = &mut headers {
+        headers.extend(validator_headers(metadata));
+    }
+    headers
+}
+
+/// Whether the conditions of a GET request tell the client's copy of the file is still
+/// current. `if-none-match` takes precedence over `if-modified-since`, like in HTTP. The
+/// error is the detail of the `BAD-REQUEST` response to send back.
+pub(crate) fn is_not_modified(
+    options: &Map<String, Value>,
+    metadata: &Metadata,
+) -> Result<bool, ErrorDetail> {
+    if let Some(tags) = options.get("if-none-match") {
+        let tags: Vec<&str> = match tags {
+            Value::String(tag) => vec![tag],
+            Value::Array(tags) => tags.iter().filter_map(Value::as_str).collect(),
+            _ => {
+                return Err(ErrorDetail::bad_options(
+                    "if-none-match must be a string or an array",
+                ))
+            }
+        };
+        let etag = etag(metadata);
+        return Ok(tags.iter().any(|tag| *tag == "*" || *tag == etag));
+    }
+    match options.get("if-modified-since") {
+        None => Ok(false),
+        Some(since) => {
+            let since = since.as_u64().ok_or_else(|| {
+                ErrorDetail::bad_options(
+                    "if-modified-since must be a number of seconds since the epoch",
+                )
+            })?;
+            Ok(last_modified(metadata).is_some_and(|last_modified| last_modified <= since))
+        }
+    }
+}
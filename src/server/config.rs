@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Content of the JSON file given by `--config`. Every field is optional,
+/// values given on the command line are merged on top of these.
+///
+/// # Example
+/// ```json
+/// {
+///     "allow": ["10.1.0.0/16", "fd00:1::/64"],
+///     "deny": ["10.1.99.0/24"],
+///     "access-log": "/var/log/ssftp/access.log",
+///     "access-log-max-size": 10485760,
+///     "access-log-keep": 5,
+///     "metrics": "127.0.0.1:9184",
+///     "admin-socket": "/run/ssftp/admin.sock",
+///     "sendfile": true,
+///     "compression": true,
+///     "find-max-depth": 32,
+///     "find-max-results": 10000,
+///     "find-time-limit": 10,
+///     "hide-dotfiles": false,
+///     "ignore-files": true
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    /// CIDR ranges allowed to connect, empty means everyone.
+    pub allow: Vec<String>,
+    /// CIDR ranges refused even when they are allowed.
+    pub deny: Vec<String>,
+    /// Path of the access log, disabled if not given.
+    pub access_log: Option<PathBuf>,
+    /// Size in bytes after which the access log is rotated.
+    pub access_log_max_size: Option<u64>,
+    /// Number of rotated access log files to keep.
+    pub access_log_keep: Option<usize>,
+    /// Address to serve Prometheus metrics on.
+    pub metrics: Option<SocketAddr>,
+    /// Path of the Unix socket accepting admin commands.
+    pub admin_socket: Option<PathBuf>,
+    /// Whether to send files with sendfile(2), default to true.
+    pub sendfile: Option<bool>,
+    /// Whether to compress payloads for clients accepting it, default to true.
+    pub compression: Option<bool>,
+    /// Number of directory levels a FIND search walks at most.
+    pub find_max_depth: Option<usize>,
+    /// Number of results after which a FIND search stops.
+    pub find_max_results: Option<usize>,
+    /// Seconds after which a FIND search stops.
+    pub find_time_limit: Option<u64>,
+    /// Whether to hide the entries whose name starts with a `.`, such as `.env` or
+    /// `.git`, default to true.
+    pub hide_dotfiles: Option<bool>,
+    /// Whether to hide what `.ssftpignore` files exclude, default to true.
+    pub ignore_files: Option<bool>,
+}
+
+impl ConfigFile {
+    /// Read and parse the config file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Entry {
+    peer: SocketAddr,
+    accepted: Instant,
+    request: Option<(&'static str, PathBuf)>,
+    bytes_sent: Arc<AtomicU64>,
+    /// A handle to the client socket, used to kill the connection.
+    stream: TcpStream,
+}
+
+/// A point-in-time view of an active connection.
+pub struct ConnectionSummary {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub method: Option<&'static str>,
+    pub path: Option<PathBuf>,
+    pub bytes_sent: u64,
+    pub elapsed: Duration,
+}
+
+/// Registry of the connections accepted and not yet closed, for live inspection.
+#[derive(Default)]
+pub struct ActiveConnections {
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl ActiveConnections {
+    pub fn insert(&self, id: u64, peer: SocketAddr, stream: TcpStream, bytes_sent: Arc<AtomicU64>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let entry = Entry {
+                peer,
+                accepted: Instant::now(),
+                request: None,
+                bytes_sent,
+                stream,
+            };
+            entries.insert(id, entry);
+        }
+    }
+
+    /// Attach the parsed request to a connection.
+    pub fn set_request(&self, id: u64, method: &'static str, path: &Path) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if let Some(entry) = entries.get_mut(&id) {
+                entry.request = Some((method, PathBuf::from(path)));
+            }
+        }
+    }
+
+    pub fn remove(&self, id: u64) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(&id);
+        }
+    }
+
+    /// Shut down the socket of a connection, the worker serving it will fail on its
+    /// next read or write. Return false if there is no such connection.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.entries.lock() {
+            Ok(entries) => match entries.get(&id) {
+                Some(entry) => {
+                    let _ = entry.stream.shutdown(Shutdown::Both);
+                    true
+                }
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        match self.entries.lock() {
+            Ok(entries) => entries
+                .iter()
+                .map(|(id, entry)| ConnectionSummary {
+                    id: *id,
+                    peer: entry.peer,
+                    method: entry.request.as_ref().map(|(method, _)| *method),
+                    path: entry.request.as_ref().map(|(_, path)| path.clone()),
+                    bytes_sent: entry.bytes_sent.load(Ordering::Relaxed),
+                    elapsed: entry.accepted.elapsed(),
+                })
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
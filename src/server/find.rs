@@ -0,0 +1,176 @@
+//! The FIND method: search a subtree for entries by name, size and modification time.
+//! Searches are bounded by [`FindLimits`] so that one of them cannot hold a worker for
+//! long, a search stopped by a limit tells why in its `truncated` header.
+
+use crate::hidden::Hiding;
+use crate::listing::{Entries, Entry, Patterns};
+use crate::server::encode_payload;
+use ssftp::encoding::Encoding;
+use ssftp::protocol::ErrorDetail;
+
+use std::fs::ReadDir;
+use std::io::{self, Write};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+/// Bounds of every FIND search and recursive listing, from the server configuration.
+/// FIND requests can only ask for lower ones.
+#[derive(Debug, Clone, Copy)]
+pub struct FindLimits {
+    /// Number of directory levels walked, 1 for the searched directory only.
+    pub max_depth: usize,
+    /// Number of results after which the search stops.
+    pub max_results: usize,
+    /// Time after which the search stops.
+    pub time_limit: Duration,
+}
+
+impl Default for FindLimits {
+    fn default() -> Self {
+        FindLimits {
+            max_depth: 32,
+            max_results: 10_000,
+            time_limit: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The criteria of a FIND request, an entry must match all of them.
+#[derive(Debug, Clone)]
+pub(crate) struct FindQuery {
+    name: Option<Patterns>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Bounds of the modification time, in seconds since the Unix epoch.
+    modified_after: Option<u64>,
+    modified_before: Option<u64>,
+    limits: FindLimits,
+}
+
+impl FindQuery {
+    /// Read the options of a FIND request. The error is the detail of the `BAD-REQUEST`
+    /// response to send back.
+    pub(crate) fn from_options(
+        options: &Map<String, Value>,
+        limits: FindLimits,
+    ) -> Result<FindQuery, ErrorDetail> {
+        let max_depth = u64_option(options, "max-depth")?.filter(|depth| *depth > 0);
+        let max_results = u64_option(options, "max-results")?.filter(|count| *count > 0);
+        Ok(FindQuery {
+            name: Patterns::from_option(options, "name")?,
+            min_size: u64_option(options, "min-size")?,
+            max_size: u64_option(options, "max-size")?,
+            modified_after: u64_option(options, "modified-after")?,
+            modified_before: u64_option(options, "modified-before")?,
+            limits: FindLimits {
+                max_depth: max_depth.map_or(limits.max_depth, |depth| {
+                    limits.max_depth.min(depth as usize)
+                }),
+                max_results: max_results.map_or(limits.max_results, |count| {
+                    limits.max_results.min(count as usize)
+                }),
+                time_limit: limits.time_limit,
+            },
+        })
+    }
+
+    /// Walk the subtree under the searched directory, within the depth limit.
+    pub(crate) fn entries(&self, read_dir: ReadDir, hiding: Hiding) -> Entries {
+        Entries::new(read_dir, hiding, None, None, self.limits.max_depth)
+    }
+
+    /// The result line of the entry if it matches, a JSON object with its path and
+    /// metadata. Directories never match a size range.
+    fn result(&self, entry: &Entry) -> Option<Value> {
+        if let Some(name) = &self.name {
+            if !name.is_match(entry) {
+                return None;
+            }
+        }
+        let metadata = entry.dir_entry.metadata().ok()?;
+        let mtime = metadata.modified().ok()?;
+        let mtime = mtime.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let in_range = |value, min: Option<u64>, max: Option<u64>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        if !in_range(mtime, self.modified_after, self.modified_before) {
+            return None;
+        }
+
+        if entry.is_dir {
+            if self.min_size.is_some() || self.max_size.is_some() {
+                return None;
+            }
+            Some(json!({ "path": entry.path, "type": "directory", "mtime": mtime }))
+        } else if in_range(metadata.len(), self.min_size, self.max_size) {
+            Some(json!({
+                "path": entry.path,
+                "type": "file",
+                "size": metadata.len(),
+                "mtime": mtime,
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// Search and write one result line per matching entry. Return the headers telling
+    /// how the search went: the number of results, and why it stopped early if it did.
+    pub(crate) fn write_results<W: Write + ?Sized>(
+        &self,
+        entries: Entries,
+        writer: &mut W,
+    ) -> io::Result<Map<String, Value>> {
+        let deadline = Instant::now().checked_add(self.limits.time_limit);
+        let mut count = 0;
+        let mut truncated = None;
+        for entry in entries {
+            if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                truncated = Some("time-limit");
+                break;
+            }
+            if let Some(result) = self.result(&entry) {
+                if count == self.limits.max_results {
+                    truncated = Some("max-results");
+                    break;
+                }
+                serde_json::to_writer(&mut *writer, &result)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+
+        let mut summary = Map::new();
+        summary.insert("count".into(), json!(count));
+        if let Some(truncated) = truncated {
+            summary.insert("truncated".into(), json!(truncated));
+        }
+        Ok(summary)
+    }
+}
+
+/// Headers and payload of the response to a FIND request sent in one piece, compressed
+/// with `encoding` if it is worth it.
+pub(crate) fn find_payload(
+    entries: Entries,
+    query: &FindQuery,
+    encoding: Option<Encoding>,
+) -> io::Result<(Value, Vec<u8>)> {
+    let mut results = vec![];
+    let summary = query.write_results(entries, &mut results)?;
+    let (mut headers, payload) = encode_payload(results, encoding)?;
+    if let Value::Object(headers) = &mut headers {
+        headers.extend(summary);
+    }
+    Ok((headers, payload))
+}
+
+fn u64_option(options: &Map<String, Value>, name: &str) -> Result<Option<u64>, ErrorDetail> {
+    match options.get(name) {
+        None => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            ErrorDetail::bad_options(format!("{} must be an unsigned integer", name))
+        }),
+    }
+}
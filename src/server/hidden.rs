@@ -0,0 +1,230 @@
+//! Which parts of the served tree clients cannot see: dotfiles if the server is set to
+//! hide them, and what `.ssftpignore` files exclude. Hidden entries are left out of
+//! listings and searches, and requested directly they do not exist.
+//!
+//! A `.ssftpignore` file uses the gitignore syntax, its patterns apply to the directory
+//! holding it and everything under it. Patterns of deeper files take precedence, so they
+//! can re-include with `!` what a parent excludes, except under an excluded directory.
+//! Ignore files are read at each request, changes apply right away.
+
+use std::fs;
+use std::path::{Component, Path};
+
+use ignore::gitignore::Gitignore;
+use log::warn;
+
+/// Name of the per-directory ignore files, always hidden themselves when honoured.
+const IGNORE_FILE: &str = ".ssftpignore";
+
+/// What to hide from clients, from the server configuration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HidePolicy {
+    /// Hide the entries whose name starts with a `.`, and everything under them.
+    pub dotfiles: bool,
+    /// Hide what the `.ssftpignore` files exclude.
+    pub ignore_files: bool,
+}
+
+impl HidePolicy {
+    /// Read the ignore file of a directory, `None` if there is none or ignore files are
+    /// not honoured.
+    fn load(&self, dir: &Path) -> Option<Gitignore> {
+        if !self.ignore_files {
+            return None;
+        }
+        let path = dir.join(IGNORE_FILE);
+        if !fs::metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+            return None;
+        }
+        let (rules, err) = Gitignore::new(&path);
+        // the valid lines are still honoured, hiding more rather than less
+        if let Some(err) = err {
+            warn!(path:% = path.display(); "invalid ignore file: {}", err);
+        }
+        Some(rules)
+    }
+
+    /// Whether the entry at `path` is hidden by its own name or the ignore files of the
+    /// directories above it, `rules` ordered from the top one down.
+    fn hides(&self, path: &Path, is_dir: bool, rules: &[Gitignore]) -> bool {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => return false,
+        };
+        if self.dotfiles && name.starts_with('.') {
+            return true;
+        }
+        if self.ignore_files && name == IGNORE_FILE {
+            return true;
+        }
+        // the deepest ignore file with an opinion decides
+        for rules in rules.iter().rev() {
+            let matched = rules.matched(path, is_dir);
+            if !matched.is_none() {
+                return matched.is_ignore();
+            }
+        }
+        false
+    }
+
+    /// Whether `path_in_fs`, under `serve_dir`, is hidden itself or lies under a hidden
+    /// directory.
+    pub(crate) fn hides_path(&self, serve_dir: &Path, path_in_fs: &Path) -> bool {
+        if !self.dotfiles && !self.ignore_files {
+            return false;
+        }
+        let relative = match path_in_fs.strip_prefix(serve_dir) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let mut current = serve_dir.to_path_buf();
+        let mut rules: Vec<Gitignore> = self.load(&current).into_iter().collect();
+        for part in relative.components() {
+            if let Component::Normal(part) = part {
+                current.push(part);
+                let is_dir = current.is_dir();
+                if self.hides(&current, is_dir, &rules) {
+                    return true;
+                }
+                if is_dir {
+                    rules.extend(self.load(&current));
+                }
+            }
+        }
+        false
+    }
+}
+
+/// The rules hiding entries while walking a directory and its subdirectories.
+#[derive(Debug, Clone)]
+pub(crate) struct Hiding {
+    policy: HidePolicy,
+    /// Ignore files of the directories from `serve_dir` down to the one being read.
+    rules: Vec<Gitignore>,
+}
+
+impl Hiding {
+    /// The rules for walking `dir`, under `serve_dir`.
+    pub(crate) fn new(policy: HidePolicy, serve_dir: &Path, dir: &Path) -> Hiding {
+        let mut hiding = Hiding {
+            policy,
+            rules: vec![],
+        };
+        hiding.enter(serve_dir);
+        if let Ok(relative) = dir.strip_prefix(serve_dir) {
+            let mut current = serve_dir.to_path_buf();
+            for part in relative.components() {
+                current.push(part);
+                hiding.enter(&current);
+            }
+        }
+        hiding
+    }
+
+    pub(crate) fn hides(&self, path: &Path, is_dir: bool) -> bool {
+        self.policy.hides(path, is_dir, &self.rules)
+    }
+
+    /// Start reading the subdirectory `dir`. Return whether it has an ignore file, which
+    /// then applies until [`Hiding::leave`] is called.
+    pub(crate) fn enter(&mut self, dir: &Path) -> bool {
+        match self.policy.load(dir) {
+            Some(rules) => {
+                self.rules.push(rules);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Done reading the last subdirectory entered with an ignore file.
+    pub(crate) fn leave(&mut self) {
+        self.rules.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const POLICY: HidePolicy = HidePolicy {
+        dotfiles: true,
+        ignore_files: true,
+    };
+
+    /// Create a served tree in a fresh temporary directory, the files given by their
+    /// path relative to it and their content.
+    fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let serve_dir =
+            std::env::temp_dir().join(format!("ssftp-hidden-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&serve_dir);
+        for (path, content) in files {
+            let path = serve_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        serve_dir
+    }
+
+    #[test]
+    fn hides_dot_and_ignored_directories() {
+        let serve_dir = tree(
+            "directories",
+            &[
+                (".ssftpignore", "build/\n"),
+                (".git/config", ""),
+                ("build/out.o", ""),
+                ("src/main.rs", ""),
+            ],
+        );
+        assert!(POLICY.hides_path(&serve_dir, &serve_dir.join(".git/config")));
+        assert!(POLICY.hides_path(&serve_dir, &serve_dir.join("build")));
+        assert!(POLICY.hides_path(&serve_dir, &serve_dir.join("build/out.o")));
+        assert!(!POLICY.hides_path(&serve_dir, &serve_dir.join("src/main.rs")));
+        fs::remove_dir_all(serve_dir).unwrap();
+    }
+
+    #[test]
+    fn deeper_ignore_file_re_includes() {
+        let serve_dir = tree(
+            "re-include",
+            &[
+                (".ssftpignore", "*.log\n"),
+                ("logs/.ssftpignore", "!keep.log\n"),
+                ("logs/keep.log", ""),
+                ("logs/other.log", ""),
+            ],
+        );
+        assert!(!POLICY.hides_path(&serve_dir, &serve_dir.join("logs/keep.log")));
+        assert!(POLICY.hides_path(&serve_dir, &serve_dir.join("logs/other.log")));
+        fs::remove_dir_all(serve_dir).unwrap();
+    }
+
+    #[test]
+    fn hides_ignore_files() {
+        let serve_dir = tree("ignore-file", &[("a/.ssftpignore", "*.tmp\n")]);
+        let policy = HidePolicy {
+            dotfiles: false,
+            ignore_files: true,
+        };
+        assert!(policy.hides_path(&serve_dir, &serve_dir.join("a/.ssftpignore")));
+        fs::remove_dir_all(serve_dir).unwrap();
+    }
+
+    #[test]
+    fn serves_dotfiles_unless_hidden() {
+        let serve_dir = tree(
+            "dotfiles",
+            &[(".env", ""), (".git/config", ""), (".ssftpignore", "")],
+        );
+        let policy = HidePolicy {
+            dotfiles: false,
+            ignore_files: true,
+        };
+        assert!(!policy.hides_path(&serve_dir, &serve_dir.join(".env")));
+        assert!(!policy.hides_path(&serve_dir, &serve_dir.join(".git/config")));
+        assert!(POLICY.hides_path(&serve_dir, &serve_dir.join(".env")));
+        fs::remove_dir_all(serve_dir).unwrap();
+    }
+}
@@ -0,0 +1,527 @@
+//! What a DIR request lists, according to its options:
+//! - the `include` and `exclude` glob patterns and `recursive` select the entries,
+//!   they are walked lazily so listings can be streamed. Recursive listings are bounded
+//!   by the [`FindLimits`] of the server, like FIND searches.
+//! - `sort`, `limit` and `cursor` ask for a page. A page has to be sorted, so every
+//!   entry is read before answering, but only those which may be in the page are kept.
+
+use crate::find::FindLimits;
+use crate::hidden::Hiding;
+use crate::server::encode_payload;
+use ssftp::encoding::Encoding;
+use ssftp::protocol::{ErrorDetail, SortOrder};
+
+use std::collections::BinaryHeap;
+use std::fs::{DirEntry, ReadDir};
+use std::io;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde_json::{json, Map, Value};
+
+/// Options of a DIR request, announced in the `dir-options` header of the HELLO
+/// response.
+pub(crate) const DIR_OPTIONS: [&str; 6] =
+    ["sort", "limit", "cursor", "include", "exclude", "recursive"];
+
+const PAGE_OPTIONS: [&str; 3] = ["sort", "limit", "cursor"];
+
+/// Most patterns accepted in each of `include` and `exclude`, matching is done for every
+/// entry walked.
+const MAX_PATTERNS: usize = 64;
+
+/// The entries a DIR request asks for, and which part of them.
+#[derive(Debug, Clone)]
+pub(crate) struct DirQuery {
+    include: Option<Patterns>,
+    exclude: Option<Patterns>,
+    recursive: bool,
+    limits: FindLimits,
+    pub(crate) page: Option<Page>,
+}
+
+impl DirQuery {
+    /// Read the options of a DIR request, a recursive listing being bounded by `limits`.
+    /// The error is the detail of the `BAD-REQUEST` response to send back.
+    pub(crate) fn from_options(
+        options: &Map<String, Value>,
+        limits: FindLimits,
+    ) -> Result<DirQuery, ErrorDetail> {
+        let recursive = match options.get("recursive") {
+            None => false,
+            Some(Value::Bool(recursive)) => *recursive,
+            Some(_) => return Err(ErrorDetail::bad_options("recursive must be a boolean")),
+        };
+        Ok(DirQuery {
+            include: Patterns::from_option(options, "include")?,
+            exclude: Patterns::from_option(options, "exclude")?,
+            recursive,
+            limits,
+            page: Page::from_options(options)?,
+        })
+    }
+
+    /// Walk the entries of a directory selected by the query, lazily.
+    pub(crate) fn entries(&self, read_dir: ReadDir, hiding: Hiding) -> Entries {
+        let max_depth = if self.recursive {
+            self.limits.max_depth
+        } else {
+            1
+        };
+        let entries = Entries::new(
+            read_dir,
+            hiding,
+            self.include.clone(),
+            self.exclude.clone(),
+            max_depth,
+        );
+        if self.recursive {
+            entries.limit(self.limits.max_results, self.limits.time_limit)
+        } else {
+            entries
+        }
+    }
+}
+
+/// Glob patterns of the `include` or `exclude` option. Patterns with a `/` are matched
+/// against the path of the entry relative to the listed directory, the others against
+/// its name only. `*` never matches `/`, `**` does.
+#[derive(Debug, Clone)]
+pub(crate) struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    /// Read an option holding a pattern or an array of patterns.
+    pub(crate) fn from_option(
+        options: &Map<String, Value>,
+        option: &str,
+    ) -> Result<Option<Patterns>, ErrorDetail> {
+        let patterns: Vec<&Value> = match options.get(option) {
+            None => return Ok(None),
+            Some(pattern @ Value::String(_)) => vec![pattern],
+            Some(Value::Array(patterns)) if patterns.len() <= MAX_PATTERNS => {
+                patterns.iter().collect()
+            }
+            Some(_) => {
+                return Err(ErrorDetail::bad_options(format!(
+                    "{} must be a pattern or an array of at most {} patterns",
+                    option, MAX_PATTERNS
+                )))
+            }
+        };
+
+        let (mut names, mut paths) = (GlobSetBuilder::new(), GlobSetBuilder::new());
+        for pattern in patterns {
+            let pattern = pattern.as_str().ok_or_else(|| {
+                ErrorDetail::bad_options(format!("{} patterns must be strings", option))
+            })?;
+            // the patterns are only matched against entries under the listed directory,
+            // refuse those which look like they could reach out of it
+            if pattern.is_empty()
+                || pattern.starts_with('/')
+                || pattern.split('/').any(|part| part == "..")
+            {
+                return Err(ErrorDetail::bad_options(format!(
+                    "invalid pattern {:?}, it must be relative and without \"..\"",
+                    pattern
+                )));
+            }
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|err| {
+                    ErrorDetail::bad_options(format!("invalid pattern {:?}: {}", pattern, err))
+                })?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        let build = |builder: GlobSetBuilder| {
+            builder.build().map_err(|err| {
+                ErrorDetail::bad_options(format!("invalid {} patterns: {}", option, err))
+            })
+        };
+        Ok(Some(Patterns {
+            names: build(names)?,
+            paths: build(paths)?,
+        }))
+    }
+
+    pub(crate) fn is_match(&self, entry: &Entry) -> bool {
+        self.names.is_match(entry.name()) || self.paths.is_match(&entry.path)
+    }
+}
+
+/// An entry of a listing.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    /// Path relative to the listed directory, `/` separated.
+    pub(crate) path: String,
+    pub(crate) is_dir: bool,
+    pub(crate) dir_entry: DirEntry,
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    /// Line of the entry in a DIR payload, directories end with a `/`.
+    pub(crate) fn line(&self) -> String {
+        if self.is_dir {
+            format!("{}/", self.path)
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+/// Iterator over the entries of a directory and its subdirectories, which are listed
+/// right after their own entry. Hidden entries, those which cannot be read and those
+/// whose name is not UTF-8 are left out, symbolic links are not followed.
+#[derive(Debug)]
+pub(crate) struct Entries {
+    /// Directories being read, with their path relative to the listed directory and
+    /// whether they have an ignore file.
+    stack: Vec<(String, ReadDir, bool)>,
+    hiding: Hiding,
+    include: Option<Patterns>,
+    exclude: Option<Patterns>,
+    /// Number of directory levels walked, 1 for the listed directory only.
+    max_depth: usize,
+    /// Number of entries after which the walk stops, and when it stops.
+    max_entries: usize,
+    deadline: Option<Instant>,
+    count: usize,
+    truncated: Option<&'static str>,
+}
+
+impl Entries {
+    pub(crate) fn new(
+        read_dir: ReadDir,
+        hiding: Hiding,
+        include: Option<Patterns>,
+        exclude: Option<Patterns>,
+        max_depth: usize,
+    ) -> Self {
+        Entries {
+            // the ignore files of the listed directory are already in `hiding`
+            stack: vec![(String::new(), read_dir, false)],
+            hiding,
+            include,
+            exclude,
+            max_depth,
+            max_entries: usize::MAX,
+            deadline: None,
+            count: 0,
+            truncated: None,
+        }
+    }
+
+    /// Stop the walk after `max_entries` entries or once `time_limit` elapsed, the
+    /// reason is then given by [`Entries::truncated`].
+    pub(crate) fn limit(mut self, max_entries: usize, time_limit: Duration) -> Self {
+        self.max_entries = max_entries;
+        self.deadline = Instant::now().checked_add(time_limit);
+        self
+    }
+
+    /// Why the walk stopped before reading every directory, `'max-results'` or
+    /// `'time-limit'`.
+    pub(crate) fn truncated(&self) -> Option<&'static str> {
+        self.truncated
+    }
+
+    /// Headers telling how the listing went once walked: the number of entries, and why
+    /// it stopped early if it did.
+    pub(crate) fn summary(&self) -> Map<String, Value> {
+        let mut summary = Map::new();
+        summary.insert("count".into(), json!(self.count));
+        if let Some(truncated) = self.truncated {
+            summary.insert("truncated".into(), json!(truncated));
+        }
+        summary
+    }
+
+    fn stop(&mut self, reason: &'static str) {
+        self.truncated = Some(reason);
+        self.stack.clear();
+    }
+
+    /// Read the next entry of the directory on top of the stack, `None` once every
+    /// directory is read.
+    fn next_entry(&mut self) -> Option<Option<Entry>> {
+        let (prefix, read_dir, _) = self.stack.last_mut()?;
+        let dir_entry = match read_dir.next() {
+            Some(Ok(dir_entry)) => dir_entry,
+            Some(Err(_)) => return Some(None),
+            None => {
+                if let Some((_, _, true)) = self.stack.pop() {
+                    self.hiding.leave();
+                }
+                return Some(None);
+            }
+        };
+        let entry = dir_entry.file_name().into_string().ok().and_then(|name| {
+            Some(Entry {
+                path: format!("{}{}", prefix, name),
+                is_dir: dir_entry.file_type().ok()?.is_dir(),
+                dir_entry,
+            })
+        });
+        Some(entry)
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        loop {
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() > deadline)
+            {
+                self.stop("time-limit");
+            }
+            let entry = match self.next_entry()? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // neither is a hidden or excluded directory walked
+            if self.hiding.hides(&entry.dir_entry.path(), entry.is_dir) {
+                continue;
+            }
+            if let Some(exclude) = &self.exclude {
+                if exclude.is_match(&entry) {
+                    continue;
+                }
+            }
+            if entry.is_dir && self.stack.len() < self.max_depth {
+                let path = entry.dir_entry.path();
+                if let Ok(read_dir) = path.read_dir() {
+                    let has_rules = self.hiding.enter(&path);
+                    self.stack
+                        .push((format!("{}/", entry.path), read_dir, has_rules));
+                }
+            }
+            if self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(&entry))
+            {
+                if self.count == self.max_entries {
+                    self.stop("max-results");
+                    return None;
+                }
+                self.count += 1;
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// Position of an entry in a sorted listing: the size or the modification time in
+/// nanoseconds depending on the order (always 0 by name), then the name to break ties.
+type SortKey = (u128, String);
+
+/// Which part of a directory to list.
+#[derive(Debug, Clone)]
+pub(crate) struct Page {
+    sort: SortOrder,
+    limit: Option<usize>,
+    /// List the entries after this one.
+    after: Option<SortKey>,
+}
+
+impl Page {
+    /// Read the paging options of a DIR request, `None` if there is none.
+    fn from_options(options: &Map<String, Value>) -> Result<Option<Page>, ErrorDetail> {
+        if !PAGE_OPTIONS.iter().any(|name| options.contains_key(*name)) {
+            return Ok(None);
+        }
+
+        let sort = match options.get("sort") {
+            None => SortOrder::Name,
+            Some(sort) => sort
+                .as_str()
+                .and_then(|sort| sort.parse().ok())
+                .ok_or_else(|| ErrorDetail::bad_options("sort must be name, size or mtime"))?,
+        };
+        let limit = match options.get("limit") {
+            None => None,
+            Some(limit) => match limit.as_u64() {
+                Some(limit) if limit > 0 => Some(limit as usize),
+                _ => return Err(ErrorDetail::bad_options("limit must be a positive integer")),
+            },
+        };
+        let after = match options.get("cursor") {
+            None => None,
+            Some(cursor) => Some(
+                cursor
+                    .as_str()
+                    .and_then(|cursor| decode_cursor(sort, cursor))
+                    .ok_or_else(|| {
+                        ErrorDetail::bad_options("cursor does not match the sort order")
+                    })?,
+            ),
+        };
+        Ok(Some(Page { sort, limit, after }))
+    }
+
+    fn key(&self, entry: &Entry) -> Option<SortKey> {
+        let metadata = entry.dir_entry.metadata().ok()?;
+        let value = match self.sort {
+            SortOrder::Name => 0,
+            // directories sort as empty files, their own size means nothing to users
+            SortOrder::Size if metadata.is_dir() => 0,
+            SortOrder::Size => metadata.len() as u128,
+            SortOrder::Mtime => {
+                let mtime = metadata.modified().ok()?;
+                mtime.duration_since(UNIX_EPOCH).ok()?.as_nanos()
+            }
+        };
+        Some((value, entry.path.clone()))
+    }
+
+    /// The lines of the entries in the page, and the cursor of the next page if there
+    /// are entries after it.
+    fn list(&self, entries: &mut Entries) -> (Vec<String>, Option<String>) {
+        // a max-heap of the smallest entries seen, one more than the page to tell
+        // whether others follow it
+        let mut smallest = BinaryHeap::new();
+        let entries = entries
+            .filter_map(|entry| Some((self.key(&entry)?, entry.line())))
+            .filter(|(key, _)| self.after.as_ref().is_none_or(|after| key > after));
+        for entry in entries {
+            smallest.push(entry);
+            if self.limit.is_some_and(|limit| smallest.len() > limit + 1) {
+                smallest.pop();
+            }
+        }
+        let mut entries = smallest.into_sorted_vec();
+
+        let limit = self.limit.unwrap_or(entries.len());
+        let next_cursor = if entries.len() > limit {
+            Some(encode_cursor(self.sort, &entries[limit - 1].0))
+        } else {
+            None
+        };
+        entries.truncate(limit);
+        (
+            entries.into_iter().map(|(_, line)| line).collect(),
+            next_cursor,
+        )
+    }
+}
+
+/// A cursor is the sort order, the value sorted on and the path of the last entry of a
+/// page, e.g. `size:1024:a.txt`. The path comes last since it may contain `:`.
+fn encode_cursor(sort: SortOrder, (value, name): &SortKey) -> String {
+    match sort {
+        SortOrder::Name => format!("{}:{}", sort.as_str(), name),
+        _ => format!("{}:{}:{}", sort.as_str(), value, name),
+    }
+}
+
+fn decode_cursor(sort: SortOrder, cursor: &str) -> Option<SortKey> {
+    let rest = cursor.strip_prefix(sort.as_str())?.strip_prefix(':')?;
+    match sort {
+        SortOrder::Name => Some((0, rest.into())),
+        _ => {
+            let (value, name) = rest.split_once(':')?;
+            Some((value.parse().ok()?, name.into()))
+        }
+    }
+}
+
+/// Headers and payload of the response to a paginated DIR request, compressed with
+/// `encoding` if it is worth it.
+pub(crate) fn page_payload(
+    mut entries: Entries,
+    page: &Page,
+    encoding: Option<Encoding>,
+) -> io::Result<(Value, Vec<u8>)> {
+    let (lines, next_cursor) = page.list(&mut entries);
+    let (mut headers, payload) = encode_payload(lines.join("\n").into_bytes(), encoding)?;
+    headers["count"] = json!(lines.len());
+    if let Some(truncated) = entries.truncated() {
+        headers["truncated"] = json!(truncated);
+    }
+    if let Some(next_cursor) = next_cursor {
+        headers["next-cursor"] = json!(next_cursor);
+    }
+    Ok((headers, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: Value) -> Result<Option<Patterns>, ErrorDetail> {
+        let options = json!({ "include": patterns });
+        Patterns::from_option(options.as_object().unwrap(), "include")
+    }
+
+    #[test]
+    fn accepts_relative_patterns() {
+        assert!(patterns(json!("*.parquet")).unwrap().is_some());
+        assert!(patterns(json!(["x/**/*.csv", "a..b"])).unwrap().is_some());
+        let options = Map::new();
+        assert!(Patterns::from_option(&options, "include")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_patterns_escaping_the_directory() {
+        for pattern in ["/etc/passwd", "a/../b", "..", "../*", ""] {
+            let err = patterns(json!(pattern)).unwrap_err();
+            assert_eq!(err.reason, "bad-options", "{:?}", pattern);
+        }
+        assert!(patterns(json!(["*.txt", "/abs"])).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        assert!(patterns(json!(1)).is_err());
+        assert!(patterns(json!([1])).is_err());
+        assert!(patterns(json!(["a[b"])).is_err());
+        assert!(patterns(json!(vec!["*"; MAX_PATTERNS + 1])).is_err());
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let keys = [
+            (SortOrder::Name, (0, "a.txt".to_string())),
+            (SortOrder::Name, (0, "x:y/b:c.txt".to_string())),
+            (SortOrder::Size, (1024, "a:b.txt".to_string())),
+            (
+                SortOrder::Mtime,
+                (1_700_000_000_123_456_789, "d/:".to_string()),
+            ),
+        ];
+        for (sort, key) in keys {
+            let cursor = encode_cursor(sort, &key);
+            assert_eq!(decode_cursor(sort, &cursor), Some(key));
+        }
+        assert_eq!(
+            encode_cursor(SortOrder::Size, &(7, "a.txt".into())),
+            "size:7:a.txt"
+        );
+    }
+
+    #[test]
+    fn rejects_cursor_of_another_order() {
+        let cursor = encode_cursor(SortOrder::Size, &(7, "a.txt".into()));
+        assert_eq!(decode_cursor(SortOrder::Mtime, &cursor), None);
+        assert_eq!(decode_cursor(SortOrder::Name, &cursor), None);
+        assert_eq!(decode_cursor(SortOrder::Size, "size:big:a.txt"), None);
+        assert_eq!(decode_cursor(SortOrder::Size, "size"), None);
+    }
+}
@@ -1,14 +1,157 @@
+mod access_log;
+mod acl;
+#[cfg(unix)]
+mod admin;
+#[cfg(feature = "async")]
+mod async_server;
+mod conditional;
+mod config;
+mod connections;
+mod find;
+mod hidden;
+mod listing;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod sendfile;
 mod server;
-use server::SSFTPServer;
+use access_log::AccessLog;
+use acl::{cidr_validator, AccessRules};
+use config::ConfigFile;
+use find::FindLimits;
+use hidden::HidePolicy;
+use server::{SSFTPServer, ServerConfig};
+use ssftp::logging::{self, log_format_validator, log_level_validator, LogFormat};
 use ssftp::utils::socket_addr_validator;
 
+use std::error::Error;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 // cli arguments
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
-use colored::*;
+use log::{error, info, LevelFilter};
+
+fn usize_validator(s: String) -> Result<(), String> {
+    s.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("Invalid number: {}", s))
+}
+
+/// Build the server configuration from the config file (if any) and the command line.
+fn load_config(matches: &ArgMatches, serve_dir: PathBuf) -> Result<ServerConfig, Box<dyn Error>> {
+    let config_file = match matches.value_of("config") {
+        Some(path) => ConfigFile::load(Path::new(path))
+            .map_err(|err| format!("config file {}: {}", path, err))?,
+        None => ConfigFile::default(),
+    };
+
+    // rules from the command line add to those in the config file
+    let mut allow = config_file.allow;
+    allow.extend(
+        matches
+            .values_of("allow")
+            .into_iter()
+            .flatten()
+            .map(String::from),
+    );
+    let mut deny = config_file.deny;
+    deny.extend(
+        matches
+            .values_of("deny")
+            .into_iter()
+            .flatten()
+            .map(String::from),
+    );
+    let access_rules = AccessRules::new(&allow, &deny)?;
+
+    let access_log = match matches
+        .value_of("access-log")
+        .map(PathBuf::from)
+        .or(config_file.access_log)
+    {
+        Some(path) => {
+            let max_size = matches
+                .value_of("access-log-max-size")
+                .map(|s| s.parse().unwrap())
+                .or(config_file.access_log_max_size)
+                .unwrap_or(10 * 1024 * 1024);
+            let keep = matches
+                .value_of("access-log-keep")
+                .map(|s| s.parse().unwrap())
+                .or(config_file.access_log_keep)
+                .unwrap_or(5);
+            let access_log = AccessLog::open(&path, max_size, keep)
+                .map_err(|err| format!("access log {}: {}", path.display(), err))?;
+            Some(Arc::new(access_log))
+        }
+        None => None,
+    };
+
+    let metrics_addr = match matches.value_of("metrics") {
+        Some(addr) => Some(addr.parse()?),
+        None => config_file.metrics,
+    };
+
+    let admin_socket = matches
+        .value_of("admin-socket")
+        .map(PathBuf::from)
+        .or(config_file.admin_socket);
+
+    let default_limits = FindLimits::default();
+    let find_limits = FindLimits {
+        max_depth: matches
+            .value_of("find-max-depth")
+            .map(|s| s.parse().unwrap())
+            .or(config_file.find_max_depth)
+            .unwrap_or(default_limits.max_depth),
+        max_results: matches
+            .value_of("find-max-results")
+            .map(|s| s.parse().unwrap())
+            .or(config_file.find_max_results)
+            .unwrap_or(default_limits.max_results),
+        time_limit: matches
+            .value_of("find-time-limit")
+            .map(|s| s.parse().unwrap())
+            .or(config_file.find_time_limit)
+            .map_or(default_limits.time_limit, Duration::from_secs),
+    };
+
+    Ok(ServerConfig {
+        serve_dir,
+        access_rules,
+        access_log,
+        metrics_addr,
+        admin_socket,
+        sendfile: !matches.is_present("no-sendfile") && config_file.sendfile.unwrap_or(true),
+        compression: !matches.is_present("no-compression")
+            && config_file.compression.unwrap_or(true),
+        find_limits,
+        hide: HidePolicy {
+            dotfiles: !matches.is_present("show-dotfiles")
+                && config_file.hide_dotfiles.unwrap_or(true),
+            ignore_files: !matches.is_present("no-ignore-files")
+                && config_file.ignore_files.unwrap_or(true),
+        },
+    })
+}
+
+#[cfg(feature = "async")]
+fn start_async(server: &SSFTPServer) {
+    if let Err(err) = server.start_async() {
+        error!("cannot start async server: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "async"))]
+fn start_async(_server: &SSFTPServer) {
+    error!("this server is built without the `async` feature");
+    process::exit(1);
+}
 
 fn main() {
     // cli app
@@ -42,21 +185,157 @@ fn main() {
                         Ok(())
                     }
                 }),
+        )
+        .arg(
+            Arg::with_name("async")
+                .help("serve connections on an async runtime instead of a thread pool")
+                .long("async"),
+        )
+        .arg(
+            Arg::with_name("no-sendfile")
+                .help("always copy files through user space instead of using sendfile(2)")
+                .long("no-sendfile"),
+        )
+        .arg(
+            Arg::with_name("no-compression")
+                .help("never compress payloads, even for clients accepting it")
+                .long("no-compression"),
+        )
+        .arg(
+            Arg::with_name("show-dotfiles")
+                .help("serve the files and directories whose name starts with a dot, hidden by default")
+                .long("show-dotfiles"),
+        )
+        .arg(
+            Arg::with_name("no-ignore-files")
+                .help("serve what .ssftpignore files exclude instead of hiding it")
+                .long("no-ignore-files"),
+        )
+        .arg(
+            Arg::with_name("find-max-depth")
+                .help("most directory levels a FIND search or recursive DIR walks [default: 32]")
+                .value_name("levels")
+                .long("find-max-depth")
+                .validator(usize_validator),
+        )
+        .arg(
+            Arg::with_name("find-max-results")
+                .help("stop a FIND search or recursive DIR after this many results [default: 10000]")
+                .value_name("count")
+                .long("find-max-results")
+                .validator(usize_validator),
+        )
+        .arg(
+            Arg::with_name("find-time-limit")
+                .help("stop a FIND search or recursive DIR after this many seconds [default: 10]")
+                .value_name("seconds")
+                .long("find-time-limit")
+                .validator(usize_validator),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help("path of a JSON config file")
+                .value_name("path")
+                .long("config"),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .help("only accept connections from this CIDR range, can be repeated")
+                .value_name("cidr")
+                .long("allow")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(cidr_validator),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .help("refuse connections from this CIDR range, can be repeated")
+                .value_name("cidr")
+                .long("deny")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(cidr_validator),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .help("append one line per request to this file")
+                .value_name("path")
+                .long("access-log"),
+        )
+        .arg(
+            Arg::with_name("access-log-max-size")
+                .help("rotate the access log when it grows beyond this many bytes")
+                .value_name("bytes")
+                .long("access-log-max-size")
+                .validator(usize_validator),
+        )
+        .arg(
+            Arg::with_name("access-log-keep")
+                .help("number of rotated access log files to keep")
+                .value_name("count")
+                .long("access-log-keep")
+                .validator(usize_validator),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .help("serve Prometheus metrics over HTTP on this address")
+                .value_name("ip:port")
+                .long("metrics")
+                .validator(socket_addr_validator),
+        )
+        .arg(
+            Arg::with_name("admin-socket")
+                .help("listen for admin commands on this Unix socket")
+                .value_name("path")
+                .long("admin-socket"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .help("most verbose level of log records to print")
+                .value_name("level")
+                .long("log-level")
+                .default_value("info")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .validator(log_level_validator),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("format of log records")
+                .value_name("format")
+                .long("log-format")
+                .default_value("human")
+                .possible_values(&["human", "json"])
+                .validator(log_format_validator),
         );
 
     let matches = app.get_matches();
+    let log_level: LevelFilter = matches.value_of("log-level").unwrap().parse().unwrap();
+    let log_format: LogFormat = matches.value_of("log-format").unwrap().parse().unwrap();
+    logging::init(log_level, log_format).unwrap();
+
     let socket_addr: SocketAddr = matches.value_of("host").unwrap().parse().unwrap();
-    let serve_dir = Path::new(matches.value_of("serve_dir").unwrap());
+    let serve_dir = PathBuf::from(matches.value_of("serve_dir").unwrap());
     let thread_count = matches
         .value_of("thread")
         .unwrap()
         .parse::<usize>()
         .unwrap();
 
-    let server = SSFTPServer::new(socket_addr, serve_dir, thread_count);
-    println!(
-        "Server starts serving at {}",
-        socket_addr.to_string().yellow().bold()
-    );
-    server.start();
+    let config = load_config(&matches, serve_dir.clone()).unwrap_or_else(|err| {
+        error!("cannot load configuration: {}", err);
+        process::exit(1);
+    });
+    let mut server = SSFTPServer::new(socket_addr, config, thread_count).unwrap_or_else(|err| {
+        error!(addr:% = socket_addr; "cannot listen: {}", err);
+        process::exit(1);
+    });
+    // the config file is read again, command line arguments stay the same
+    let reload_matches = matches.clone();
+    server.set_reloader(move || load_config(&reload_matches, serve_dir.clone()));
+    info!(addr:% = socket_addr; "server starts serving");
+    if matches.is_present("async") {
+        start_async(&server);
+    } else {
+        server.start();
+    }
 }
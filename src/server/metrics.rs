@@ -0,0 +1,210 @@
+use ssftp::StatusCode;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// Upper bounds (in seconds) of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters of the server, rendered in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, String), u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    bytes_served: AtomicU64,
+    active_connections: AtomicU64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Record a finished request. `method` is `None` for requests that could not be parsed.
+    pub fn record_request(
+        &self,
+        method: Option<&'static str>,
+        status: StatusCode,
+        bytes_sent: u64,
+        duration: Duration,
+    ) {
+        let method = method.unwrap_or("-");
+        self.bytes_served.fetch_add(bytes_sent, Ordering::Relaxed);
+        if let Ok(mut requests) = self.requests.lock() {
+            *requests.entry((method, status.to_string())).or_insert(0) += 1;
+        }
+        if let Ok(mut latency) = self.latency.lock() {
+            latency
+                .entry(method)
+                .or_default()
+                .observe(duration.as_secs_f64());
+        }
+    }
+
+    /// Render all metrics, `pool` is only read for its queue depth and active threads.
+    /// It is `None` in async mode, which has no thread pool to report on.
+    pub fn render(&self, pool: Option<&threadpool::ThreadPool>) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP ssftp_requests_total Requests served, by method and status code.\n");
+        out.push_str("# TYPE ssftp_requests_total counter\n");
+        if let Ok(requests) = self.requests.lock() {
+            for ((method, status), count) in requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "ssftp_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                    method, status, count
+                );
+            }
+        }
+
+        out.push_str("# HELP ssftp_bytes_served_total Bytes written to clients.\n");
+        out.push_str("# TYPE ssftp_bytes_served_total counter\n");
+        let _ = writeln!(
+            out,
+            "ssftp_bytes_served_total {}",
+            self.bytes_served.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP ssftp_active_connections Connections accepted and not yet closed.\n");
+        out.push_str("# TYPE ssftp_active_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "ssftp_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        if let Some(pool) = pool {
+            out.push_str(
+                "# HELP ssftp_thread_pool_queued Connections waiting for a worker thread.\n",
+            );
+            out.push_str("# TYPE ssftp_thread_pool_queued gauge\n");
+            let _ = writeln!(out, "ssftp_thread_pool_queued {}", pool.queued_count());
+
+            out.push_str(
+                "# HELP ssftp_thread_pool_active Worker threads busy with a connection.\n",
+            );
+            out.push_str("# TYPE ssftp_thread_pool_active gauge\n");
+            let _ = writeln!(out, "ssftp_thread_pool_active {}", pool.active_count());
+        }
+
+        out.push_str("# HELP ssftp_request_duration_seconds Time from accepting a connection to the end of its response.\n");
+        out.push_str("# TYPE ssftp_request_duration_seconds histogram\n");
+        if let Ok(latency) = self.latency.lock() {
+            for (method, histogram) in latency.iter() {
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    let _ = writeln!(
+                        out,
+                        "ssftp_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                        method, bound, count
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "ssftp_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                    method, histogram.count
+                );
+                let _ = writeln!(
+                    out,
+                    "ssftp_request_duration_seconds_sum{{method=\"{}\"}} {}",
+                    method, histogram.sum
+                );
+                let _ = writeln!(
+                    out,
+                    "ssftp_request_duration_seconds_count{{method=\"{}\"}} {}",
+                    method, histogram.count
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// Answer one HTTP request of the scraper, only `GET /metrics` is served.
+fn handle_scrape(
+    stream: TcpStream,
+    metrics: &Metrics,
+    pool: Option<&threadpool::ThreadPool>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the request headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render(pool)),
+        _ => ("404 Not Found", "not found\n".into()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Start serving the metrics on `addr` from a background thread, with the gauges of
+/// `pool` if connections are served by one.
+pub fn serve(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    pool: Option<threadpool::ThreadPool>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(addr:% = addr; "metrics listener started");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_scrape(stream, &metrics, pool.as_ref()));
+            if let Err(err) = result {
+                warn!("error occured while serving metrics: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
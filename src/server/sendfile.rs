@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+
+/// Largest count accepted by a single sendfile(2) call without being truncated, it is
+/// also how often progress is reported.
+const MAX_CHUNK: usize = 1 << 20;
+
+/// Copy up to `len` bytes of `file`, from its current offset, to `stream` inside the
+/// kernel with sendfile(2). `progress` is called with the number of bytes sent by each
+/// chunk. Return the number of bytes sent, which is less than `len` only if the file
+/// was truncated.
+///
+/// An error of kind `Unsupported` means nothing was sent and the caller should fall
+/// back to copying through user space.
+pub fn sendfile(
+    stream: &TcpStream,
+    file: &File,
+    len: u64,
+    mut progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut sent = 0;
+    while sent < len {
+        let count = (len - sent).min(MAX_CHUNK as u64) as usize;
+        let result = unsafe {
+            libc::sendfile(
+                stream.as_raw_fd(),
+                file.as_raw_fd(),
+                std::ptr::null_mut(),
+                count,
+            )
+        };
+
+        match result {
+            0 => break,
+            n if n > 0 => {
+                sent += n as u64;
+                progress(n as u64);
+            }
+            _ => {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::EINVAL) | Some(libc::ENOSYS) if sent == 0 => {
+                        return Err(io::Error::new(io::ErrorKind::Unsupported, err));
+                    }
+                    _ => return Err(err),
+                }
+            }
+        }
+    }
+    Ok(sent)
+}
@@ -1,296 +1,1022 @@
-use ssftp::utils::sanitize_request_path;
+use crate::access_log::{AccessEntry, AccessLog};
+use crate::acl::AccessRules;
+#[cfg(unix)]
+use crate::admin::{self, AdminContext};
+use crate::conditional::{is_not_modified, with_validators};
+use crate::connections::ActiveConnections;
+use crate::find::{find_payload, FindLimits, FindQuery};
+use crate::hidden::{HidePolicy, Hiding};
+use crate::listing::{page_payload, DirQuery, Entries, Page, DIR_OPTIONS};
+use crate::metrics::{self, Metrics};
+#[cfg(target_os = "linux")]
+use crate::sendfile::sendfile;
+use ssftp::encoding::{Encoding, SAMPLE_SIZE};
+use ssftp::protocol::{
+    ChunkedWriter, ErrorDetail, Method, Request, ResponseHead, MAX_REQUEST_LINE,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use ssftp::StatusCode;
 
 use std::error::Error;
 use std::fs::{File, Metadata, ReadDir};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
-use colored::*; // cli output coloring
+use log::{debug, error, info, warn};
 use serde_json::json;
-use threadpool;
 
 pub struct SSFTPServer {
-    config: Arc<Mutex<ServerConfig>>,
-    listener: TcpListener,
-    thread_pool: threadpool::ThreadPool,
+    pub(crate) config: Arc<Mutex<ServerConfig>>,
+    pub(crate) listener: TcpListener,
+    pub(crate) thread_pool: threadpool::ThreadPool,
+    next_connection_id: AtomicU64,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) connections: Arc<ActiveConnections>,
+    reloader: Option<Arc<Reloader>>,
 }
 
-struct ServerConfig {
-    serve_dir: PathBuf,
+/// Builds a fresh configuration when a reload is requested.
+pub type Reloader = dyn Fn() -> Result<ServerConfig, Box<dyn Error>> + Send + Sync;
+
+/// Settings shared by all the worker threads.
+pub struct ServerConfig {
+    pub serve_dir: PathBuf,
+    pub access_rules: AccessRules,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Address of the Prometheus metrics listener, only read when the server starts.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Path of the admin Unix socket, only read when the server starts.
+    pub admin_socket: Option<PathBuf>,
+    /// Send files with sendfile(2) where available instead of copying them through
+    /// user space. Only used by the thread pool server.
+    pub sendfile: bool,
+    /// Compress GET and DIR payloads for clients accepting it.
+    pub compression: bool,
+    /// Bounds of FIND searches.
+    pub find_limits: FindLimits,
+    /// Entries hidden from clients.
+    pub hide: HidePolicy,
 }
 
-/// Contains all needed information of an incoming request for preparation of response
-#[derive(Debug)]
-enum Request {
-    Get(PathBuf),
-    Info(PathBuf),
-    Dir(PathBuf),
+/// Largest file compressed in memory, for clients which do not accept chunked framing
+/// and so need the compressed length up front. Larger files are sent to them as is,
+/// while clients accepting chunked framing get them compressed on the fly.
+pub(crate) const MAX_COMPRESS_SIZE: u64 = 1024 * 1024;
+
+/// An accepted connection, its fields are attached to every log record about it.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    pub(crate) id: u64,
+    pub(crate) peer: SocketAddr,
+    accepted_at: SystemTime,
+    accepted: Instant,
+    pub(crate) bytes_sent: Arc<AtomicU64>,
+}
+
+impl Connection {
+    /// Bookkeeping once the response is done or aborted: metrics, access log and the
+    /// log record of the request.
+    pub(crate) fn finish(
+        &self,
+        request: &Result<Request, ErrorDetail>,
+        status: StatusCode,
+        aborted: bool,
+        access_log: Option<Arc<AccessLog>>,
+        metrics: &Metrics,
+    ) {
+        let request = request.as_ref().ok();
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        let duration = self.accepted.elapsed();
+        metrics.record_request(
+            request.map(|r| r.method.as_str()),
+            status,
+            bytes_sent,
+            duration,
+        );
+
+        if let Some(access_log) = access_log {
+            let entry = AccessEntry {
+                peer: self.peer,
+                start: self.accepted_at,
+                request: request.map(|r| (r.method.as_str(), r.path.as_path())),
+                status,
+                bytes_sent,
+                duration,
+                aborted,
+            };
+            if let Err(err) = access_log.record(&entry) {
+                error!("cannot write access log: {}", err);
+            }
+        }
+
+        if let (Some(request), false) = (request, aborted) {
+            info!(
+                peer:% = self.peer,
+                rid = self.id,
+                method = request.method.as_str(),
+                path:% = request.path.display(),
+                status:% = status;
+                "request served"
+            );
+        }
+    }
+}
+
+/// Wraps the client stream to count the bytes written to it, the counter can be
+/// watched from other threads while the response is in progress.
+pub(crate) struct CountingWriter<W> {
+    pub(crate) inner: W,
+    pub(crate) count: Arc<AtomicU64>,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(inner: W, count: Arc<AtomicU64>) -> Self {
+        CountingWriter { inner, count }
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How the payload of a GET or DIR response may be sent, negotiated with the request
+/// options.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Framing {
+    pub(crate) encoding: Option<Encoding>,
+    pub(crate) chunked: bool,
 }
 
 /// A pending response, before any performance of huge read & write
 #[derive(Debug)]
-enum PendingResponse {
-    Get(File),
+pub(crate) enum PendingResponse {
+    Get(File, Framing),
     Info(Metadata),
-    Dir(ReadDir),
-    Error(StatusCode),
-    BadRequest,
+    /// The file of a conditional GET did not change since the client's copy.
+    NotModified(Metadata),
+    Dir(Entries, Framing, Option<Page>),
+    Find(Entries, FindQuery, Framing),
+    Hello(serde_json::Value),
+    Error(StatusCode, ErrorDetail),
 }
 
-/// Parse the raw request and return the type of request.
-fn get_request(stream: &mut TcpStream) -> Result<Request, &str> {
-    let mut raw_request = vec![];
-    if BufReader::new(stream)
-        .read_until('\n' as u8, &mut raw_request)
-        .is_err()
-    {
-        return Err("read tcp stream error");
+impl PendingResponse {
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self {
+            PendingResponse::Get(..)
+            | PendingResponse::Info(_)
+            | PendingResponse::Dir(..)
+            | PendingResponse::Find(..)
+            | PendingResponse::Hello(_) => StatusCode::OK,
+            PendingResponse::NotModified(_) => StatusCode::NotModified,
+            PendingResponse::Error(status_code, _) => *status_code,
+        }
     }
 
-    raw_request.pop();
+    /// An error response, `reason` is one of the machine-readable reasons in the README.
+    pub(crate) fn error<S: Into<String>>(
+        status_code: StatusCode,
+        reason: &str,
+        message: S,
+    ) -> Self {
+        PendingResponse::Error(status_code, ErrorDetail::new(reason, message))
+    }
+}
 
-    let raw_request = String::from_utf8_lossy(&raw_request[..]);
+/// Read and decode the request, or the reason why it is bad.
+fn get_request(stream: &mut TcpStream) -> Result<Request, ErrorDetail> {
+    let mut reader = BufReader::new(stream);
+    let mut raw_request = vec![];
+    let mut raw_options = vec![];
+    let mut read_line = |line: &mut Vec<u8>| {
+        (&mut reader)
+            .take(MAX_REQUEST_LINE)
+            .read_until(b'\n', line)?;
+        check_line_length(line)
+    };
+    let read = read_line(&mut raw_request).and_then(|_| {
+        if Request::has_options_line(&raw_request) {
+            read_line(&mut raw_options)?;
+        }
+        Ok(())
+    });
+    if let Err(err) = read {
+        return Err(ErrorDetail::new(
+            "bad-request",
+            format!("cannot read the request: {}", err),
+        ));
+    }
 
-    println!("Raw request: {}", raw_request);
+    Request::decode(&raw_request, Some(&raw_options))
+}
 
-    let mut iterator = raw_request.splitn(2, ' ').into_iter();
-    match (iterator.next(), iterator.next()) {
-        (Some(method), Some(path)) => match &method.to_uppercase()[..] {
-            "GET" => Ok(Request::Get(PathBuf::from(path))),
-            "INFO" => Ok(Request::Info(PathBuf::from(path))),
-            "DIR" => Ok(Request::Dir(PathBuf::from(path))),
-            _ => Err("bad method"),
-        },
-        _ => Err("bad request"),
+/// Fail if a line read up to [`MAX_REQUEST_LINE`] bytes was cut before its end.
+pub(crate) fn check_line_length(line: &[u8]) -> io::Result<()> {
+    if line.len() as u64 == MAX_REQUEST_LINE && !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line longer than {} bytes", MAX_REQUEST_LINE),
+        ));
     }
+    Ok(())
 }
 
 impl SSFTPServer {
     /// Create a server
     /// # Arguments
     /// * `socket_addr`: the ip and port to listen on
-    /// * `serve_dir`: the root path of directory to serve file.
+    /// * `config`: the serving directory and access rules.
     /// * `thread_count`: number of thread to use.
-    pub fn new(socket_addr: SocketAddr, serve_dir: &Path, thread_count: usize) -> Self {
-        let serve_dir = PathBuf::from(serve_dir);
-        let listener = TcpListener::bind(socket_addr).unwrap();
+    ///
+    /// Fail if the address cannot be bound, e.g. when it is already in use.
+    pub fn new(
+        socket_addr: SocketAddr,
+        config: ServerConfig,
+        thread_count: usize,
+    ) -> ssftp::error::Result<Self> {
+        let listener = TcpListener::bind(socket_addr)?;
         let thread_pool = threadpool::Builder::new().num_threads(thread_count).build();
 
-        SSFTPServer {
-            config: Arc::new(Mutex::new(ServerConfig { serve_dir })),
+        Ok(SSFTPServer {
+            config: Arc::new(Mutex::new(config)),
             listener,
             thread_pool,
-        }
+            next_connection_id: AtomicU64::new(1),
+            metrics: Arc::new(Metrics::default()),
+            connections: Arc::new(ActiveConnections::default()),
+            reloader: None,
+        })
+    }
+
+    /// Set how to rebuild the configuration when a reload is requested from the
+    /// admin socket.
+    pub fn set_reloader<F>(&mut self, reloader: F)
+    where
+        F: Fn() -> Result<ServerConfig, Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.reloader = Some(Arc::new(reloader));
     }
 
     /// Block the program and start listening.
     pub fn start(&self) {
+        self.start_side_listeners(Some(&self.thread_pool));
+
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    println!(
-                        "\nIncoming connection from {}",
-                        (if let Ok(addr) = stream.peer_addr() {
-                            addr.to_string()
-                        } else {
-                            "?".into()
-                        })
-                        .yellow()
-                        .bold()
-                    );
+                    let peer_addr = match stream.peer_addr() {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            warn!("dropping connection with unknown peer address: {}", err);
+                            continue;
+                        }
+                    };
+
+                    let connection = match self.accept(peer_addr, stream.try_clone()) {
+                        Some(connection) => connection,
+                        None => continue,
+                    };
+
                     let config = Arc::clone(&self.config);
-                    self.thread_pool.execute(|| {
-                        if let Err(err) = handle_client(config, stream) {
-                            println!("Error occured while handling a client. {}", err);
+                    let metrics = Arc::clone(&self.metrics);
+                    let connections = Arc::clone(&self.connections);
+                    self.thread_pool.execute(move || {
+                        let result =
+                            handle_client(config, stream, &connection, &metrics, &connections);
+                        if let Err(err) = result {
+                            error!(
+                                peer:% = connection.peer, rid = connection.id;
+                                "error occured while handling a client: {}", err
+                            );
                         }
+                        connections.remove(connection.id);
+                        metrics.connection_closed();
                     })
                 }
 
-                Err(err) => println!("Error occured while handling a stream. {}", err),
+                Err(err) => error!("error occured while accepting a stream: {}", err),
+            }
+        }
+    }
+
+    /// Start the metrics listener and the admin socket, if they are configured. `pool`
+    /// is the thread pool serving connections, `None` in async mode.
+    pub(crate) fn start_side_listeners(&self, pool: Option<&threadpool::ThreadPool>) {
+        let (metrics_addr, admin_socket) = match self.config.lock() {
+            Ok(config) => (config.metrics_addr, config.admin_socket.clone()),
+            Err(_) => (None, None),
+        };
+
+        if let Some(addr) = metrics_addr {
+            let pool = pool.cloned();
+            if let Err(err) = metrics::serve(addr, Arc::clone(&self.metrics), pool) {
+                error!(addr:% = addr; "cannot start metrics listener: {}", err);
             }
         }
+
+        if let Some(path) = admin_socket {
+            self.serve_admin(&path);
+        }
+    }
+
+    /// Check the access rules for a new connection from `peer_addr`, and register it
+    /// if it may be served. `handle` is a clone of its stream, used to kill it.
+    pub(crate) fn accept(
+        &self,
+        peer_addr: SocketAddr,
+        handle: io::Result<TcpStream>,
+    ) -> Option<Connection> {
+        if !self.permits(peer_addr) {
+            warn!(peer:% = peer_addr; "connection rejected by access rules");
+            return None;
+        }
+
+        let connection = Connection {
+            id: self.next_connection_id.fetch_add(1, Ordering::Relaxed),
+            peer: peer_addr,
+            accepted_at: SystemTime::now(),
+            accepted: Instant::now(),
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+        };
+        debug!(peer:% = connection.peer, rid = connection.id; "connection accepted");
+
+        if let Ok(handle) = handle {
+            let bytes_sent = Arc::clone(&connection.bytes_sent);
+            self.connections
+                .insert(connection.id, peer_addr, handle, bytes_sent);
+        }
+        self.metrics.connection_opened();
+        Some(connection)
+    }
+
+    #[cfg(unix)]
+    fn serve_admin(&self, path: &Path) {
+        let context = AdminContext {
+            connections: Arc::clone(&self.connections),
+            config: Arc::clone(&self.config),
+            reloader: self.reloader.clone(),
+        };
+        if let Err(err) = admin::serve(path, context) {
+            error!(path:% = path.display(); "cannot start admin socket: {}", err);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn serve_admin(&self, path: &Path) {
+        error!(path:% = path.display(); "admin socket is only supported on Unix");
+    }
+
+    /// Check the access rules for a newly accepted connection.
+    fn permits(&self, peer_addr: SocketAddr) -> bool {
+        match self.config.lock() {
+            Ok(config) => config.access_rules.permits(peer_addr.ip()),
+            Err(_) => false,
+        }
     }
 }
 
+/// A poisoned lock means a worker panicked while holding it.
+fn lock_error() -> ssftp::Error {
+    ssftp::Error::Io(io::Error::other("multithread lock error"))
+}
+
 fn handle_client(
     config: Arc<Mutex<ServerConfig>>,
-    mut stream: TcpStream,
-) -> Result<(), Box<dyn Error>> {
-    let request = get_request(&mut stream);
+    stream: TcpStream,
+    connection: &Connection,
+    metrics: &Metrics,
+    connections: &ActiveConnections,
+) -> ssftp::error::Result<()> {
+    let (access_log, use_sendfile) = match config.lock() {
+        Ok(config) => (config.access_log.clone(), config.sendfile),
+        Err(_) => return Err(lock_error()),
+    };
 
-    let response;
-    if let Ok(request) = request {
-        response = prepare_response(config, &request)?;
-    } else {
-        response = PendingResponse::BadRequest;
+    let mut stream = CountingWriter::new(stream, Arc::clone(&connection.bytes_sent));
+    let request = get_request(stream.get_mut());
+    if let Ok(request) = &request {
+        connections.set_request(connection.id, request.method.as_str(), &request.path);
     }
 
-    perform_response(stream, response)?;
-    Ok(())
+    let response = prepare(&config, &request, connection);
+    let status = response.status_code();
+    let result = perform_response(&mut stream, response, use_sendfile);
+    connection.finish(&request, status, result.is_err(), access_log, metrics);
+    result
+}
+
+/// Prepare the response of a parsed request, or the error response of a bad one.
+pub(crate) fn prepare(
+    config: &Mutex<ServerConfig>,
+    request: &Result<Request, ErrorDetail>,
+    connection: &Connection,
+) -> PendingResponse {
+    match request {
+        Ok(request) => prepare_response(config, request).unwrap_or_else(|err| {
+            error!(peer:% = connection.peer, rid = connection.id; "cannot prepare response: {}", err);
+            PendingResponse::error(StatusCode::ServerError, "server-error", "internal error")
+        }),
+        Err(detail) => {
+            warn!(
+                peer:% = connection.peer,
+                rid = connection.id,
+                reason = detail.reason.as_str();
+                "bad request: {}", detail.message
+            );
+            PendingResponse::Error(StatusCode::BadRequest, detail.clone())
+        }
+    }
 }
 
 fn prepare_response(
-    config: Arc<Mutex<ServerConfig>>,
+    config: &Mutex<ServerConfig>,
     request: &Request,
-) -> Result<PendingResponse, Box<dyn Error>> {
-    match request {
-        Request::Get(path) | Request::Info(path) | Request::Dir(path) => {
-            if !sanitize_request_path(path) {
-                return Ok(PendingResponse::BadRequest);
-            }
+) -> ssftp::error::Result<PendingResponse> {
+    let (serve_dir, compression, find_limits, hide) = match config.lock() {
+        Ok(config) => (
+            config.serve_dir.clone(),
+            config.compression,
+            config.find_limits,
+            config.hide,
+        ),
+        Err(_) => return Err(lock_error()),
+    };
+    if request.method == Method::Hello {
+        return Ok(PendingResponse::Hello(hello_headers(compression)));
+    }
+    let framing = Framing {
+        encoding: if compression {
+            Encoding::negotiate(request.options.get("accept-encoding"))
+        } else {
+            None
+        },
+        chunked: request.options.get("accept-chunked") == Some(&json!(true)),
+    };
 
-            let path_in_fs = if let Ok(config) = config.lock() {
-                config.serve_dir.join(path.strip_prefix("/")?)
-            } else {
-                return Err("Multithread lock error".into());
-            };
+    let path = &request.path;
+    if !path.has_root() {
+        return Ok(PendingResponse::error(
+            StatusCode::BadRequest,
+            "relative-path",
+            format!("the path {} does not start with \"/\"", path.display()),
+        ));
+    }
+    if path.components().any(|part| part == Component::ParentDir) {
+        return Ok(PendingResponse::error(
+            StatusCode::BadRequest,
+            "parent-path",
+            format!("the path {} contains \"..\"", path.display()),
+        ));
+    }
 
-            if !path_in_fs.exists() {
-                return Ok(PendingResponse::Error(StatusCode::NotExist));
-            }
+    let relative_path = path
+        .strip_prefix("/")
+        .map_err(|_| ssftp::Error::InvalidPath(path.clone()))?;
+    let path_in_fs = serve_dir.join(relative_path);
+
+    // hidden entries are not told apart from missing ones
+    if !path_in_fs.exists() || hide.hides_path(&serve_dir, &path_in_fs) {
+        return Ok(PendingResponse::error(
+            StatusCode::NotExist,
+            "not-exist",
+            format!("{} does not exist", path.display()),
+        ));
+    }
 
-            match request {
-                Request::Get(_) => {
-                    if let Ok(file) = File::open(path_in_fs) {
-                        if !file.metadata()?.is_file() {
-                            Ok(PendingResponse::Error(StatusCode::NotFile))
-                        } else {
-                            Ok(PendingResponse::Get(file))
-                        }
-                    } else {
-                        Ok(PendingResponse::Error(StatusCode::ServerError))
+    match request.method {
+        Method::Get => {
+            if let Ok(file) = File::open(path_in_fs) {
+                let metadata = file.metadata()?;
+                if !metadata.is_file() {
+                    Ok(PendingResponse::error(
+                        StatusCode::NotFile,
+                        "not-file",
+                        format!("{} is not a file", path.display()),
+                    ))
+                } else {
+                    match is_not_modified(&request.options, &metadata) {
+                        Ok(true) => Ok(PendingResponse::NotModified(metadata)),
+                        Ok(false) => Ok(PendingResponse::Get(file, framing)),
+                        Err(detail) => Ok(PendingResponse::Error(StatusCode::BadRequest, detail)),
                     }
                 }
+            } else {
+                Ok(PendingResponse::error(
+                    StatusCode::ServerError,
+                    "server-error",
+                    format!("cannot open {}", path.display()),
+                ))
+            }
+        }
 
-                Request::Info(_) => {
-                    if let Ok(metadata) = path_in_fs.metadata() {
-                        Ok(PendingResponse::Info(metadata))
-                    } else {
-                        Ok(PendingResponse::Error(StatusCode::ServerError))
-                    }
-                }
+        Method::Info => {
+            if let Ok(metadata) = path_in_fs.metadata() {
+                Ok(PendingResponse::Info(metadata))
+            } else {
+                Ok(PendingResponse::error(
+                    StatusCode::ServerError,
+                    "server-error",
+                    format!("cannot read the metadata of {}", path.display()),
+                ))
+            }
+        }
 
-                Request::Dir(_) => {
-                    if let Ok(metadata) = path_in_fs.metadata() {
-                        if !metadata.is_dir() {
-                            return Ok(PendingResponse::Error(StatusCode::NotDirectory));
-                        }
-                    }
+        Method::Hello => unreachable!("answered before looking at the path"),
 
-                    if let Ok(read_dir) = path_in_fs.read_dir() {
-                        Ok(PendingResponse::Dir(read_dir))
-                    } else {
-                        Ok(PendingResponse::Error(StatusCode::ServerError))
-                    }
-                }
+        Method::Dir => {
+            let query = match DirQuery::from_options(&request.options, find_limits) {
+                Ok(query) => query,
+                Err(detail) => return Ok(PendingResponse::Error(StatusCode::BadRequest, detail)),
+            };
+            match read_dir(&path_in_fs, path) {
+                Ok(read_dir) => Ok(PendingResponse::Dir(
+                    query.entries(read_dir, Hiding::new(hide, &serve_dir, &path_in_fs)),
+                    framing,
+                    query.page,
+                )),
+                Err((status_code, detail)) => Ok(PendingResponse::Error(status_code, detail)),
+            }
+        }
+
+        Method::Find => {
+            let query = match FindQuery::from_options(&request.options, find_limits) {
+                Ok(query) => query,
+                Err(detail) => return Ok(PendingResponse::Error(StatusCode::BadRequest, detail)),
+            };
+            match read_dir(&path_in_fs, path) {
+                Ok(read_dir) => Ok(PendingResponse::Find(
+                    query.entries(read_dir, Hiding::new(hide, &serve_dir, &path_in_fs)),
+                    query,
+                    framing,
+                )),
+                Err((status_code, detail)) => Ok(PendingResponse::Error(status_code, detail)),
             }
         }
     }
 }
 
+/// Open the directory at `path_in_fs` for a DIR or FIND request on `path`, or the error
+/// to respond with.
+fn read_dir(path_in_fs: &Path, path: &Path) -> Result<ReadDir, (StatusCode, ErrorDetail)> {
+    if let Ok(metadata) = path_in_fs.metadata() {
+        if !metadata.is_dir() {
+            return Err((
+                StatusCode::NotDirectory,
+                ErrorDetail::new(
+                    "not-directory",
+                    format!("{} is not a directory", path.display()),
+                ),
+            ));
+        }
+    }
+
+    path_in_fs.read_dir().map_err(|_| {
+        (
+            StatusCode::ServerError,
+            ErrorDetail::new(
+                "server-error",
+                format!("cannot read the directory {}", path.display()),
+            ),
+        )
+    })
+}
+
 /// Perform a pending response given by `response`, write through the internet.
 /// Act as a a dispatcher function.
-fn perform_response(stream: TcpStream, response: PendingResponse) -> Result<(), Box<dyn Error>> {
+fn perform_response(
+    stream: &mut CountingWriter<TcpStream>,
+    response: PendingResponse,
+    use_sendfile: bool,
+) -> ssftp::error::Result<()> {
     use PendingResponse::*;
     match response {
-        Get(file) => perform_get_response(stream, file),
+        Get(file, framing) => perform_get_response(stream, file, framing, use_sendfile),
         Info(metadata) => perform_info_response(stream, metadata),
-        Dir(read_dir) => perform_dir_response(stream, read_dir),
-        Error(status_code) => perform_error_response(stream, status_code),
-        BadRequest => perform_bad_request_response(stream),
+        NotModified(metadata) => perform_not_modified_response(stream, metadata),
+        Dir(entries, framing, page) => perform_dir_response(stream, entries, framing, page),
+        Find(entries, query, framing) => perform_find_response(stream, entries, &query, framing),
+        Hello(headers) => perform_hello_response(stream, headers),
+        Error(status_code, detail) => perform_error_response(stream, status_code, &detail),
     }
 }
 
-fn perform_get_response(stream: TcpStream, file: File) -> Result<(), Box<dyn Error>> {
-    println!("Performing {} response", "GET".green().bold());
+fn perform_get_response(
+    stream: &mut CountingWriter<TcpStream>,
+    mut file: File,
+    framing: Framing,
+    use_sendfile: bool,
+) -> ssftp::error::Result<()> {
+    let metadata = file.metadata()?;
+    let content_length = metadata.len();
+    match framing.encoding {
+        Some(encoding) if framing.chunked && content_length > SAMPLE_SIZE as u64 => {
+            if let Some(sample) = sample_file(&mut file, encoding)? {
+                let mut writer = BufWriter::new(stream);
+                let headers = compressed_file_headers(&metadata, encoding);
+                ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+                write_compressed_file(&mut file, &metadata, &sample, encoding, writer)?.flush()?;
+                return Ok(());
+            }
+        }
+        Some(encoding) if content_length <= MAX_COMPRESS_SIZE => {
+            let mut content = Vec::with_capacity(content_length as usize);
+            (&mut file).take(content_length).read_to_end(&mut content)?;
+            let mut writer = BufWriter::new(stream);
+            if content.len() as u64 != content_length || !is_unchanged(&file, &metadata) {
+                let detail = file_changed_detail();
+                ResponseHead::new(StatusCode::ServerError, detail.to_headers())
+                    .write_to(&mut writer)?;
+                writer.flush()?;
+                return Err(file_changed_error().into());
+            }
+            let (headers, payload) = encode_payload(content, Some(encoding))?;
+            let headers = file_headers(headers, &metadata);
 
-    let content_length = file.metadata()?.len();
-    let headers = json!({
-        "content-length": content_length,
-    });
+            ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+            writer.write_all(&payload)?;
+            writer.flush()?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let headers = file_headers(json!({ "content-length": content_length }), &metadata);
 
     let mut writer = BufWriter::new(stream);
-    writer.write(StatusCode::OK.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
-    writer.write(headers.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
+    ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+
+    // the last byte is held back until the file is checked, the transfer is aborted
+    // if the file changed so that the client gets less than `content-length`
+    let body_length = content_length.saturating_sub(1);
+    let mut sent = None;
+    #[cfg(target_os = "linux")]
+    if use_sendfile {
+        writer.flush()?;
+        let stream = writer.get_mut();
+        let count = Arc::clone(&stream.count);
+        let progress = |sent| {
+            count.fetch_add(sent, Ordering::Relaxed);
+        };
+        match sendfile(&stream.inner, &file, body_length, progress) {
+            Ok(count) => sent = Some(count),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                debug!("falling back to buffered copy: {}", err);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = use_sendfile;
+
+    let sent = match sent {
+        Some(sent) => sent,
+        None => io::copy(
+            &mut BufReader::new((&mut file).take(body_length)),
+            &mut writer,
+        )?,
+    };
+    if sent != body_length || !is_unchanged(&file, &metadata) {
+        return Err(file_changed_error().into());
+    }
+    let last = io::copy(
+        &mut (&mut file).take(content_length - body_length),
+        &mut writer,
+    )?;
+    if last != content_length - body_length {
+        return Err(file_changed_error().into());
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the start of `file` to tell whether compressing it is worth it. Return the
+/// sample if it is, otherwise rewind the file to send it as is.
+pub(crate) fn sample_file(file: &mut File, encoding: Encoding) -> io::Result<Option<Vec<u8>>> {
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    file.take(SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    if encoding.compresses_well(&sample)? {
+        return Ok(Some(sample));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(None)
+}
+
+/// Headers of a file streamed compressed with chunked framing, see
+/// [`write_compressed_file`].
+pub(crate) fn compressed_file_headers(
+    metadata: &Metadata,
+    encoding: Encoding,
+) -> serde_json::Value {
+    let mut headers = file_headers(chunked_headers(Some(encoding)), metadata);
+    headers["original-length"] = json!(metadata.len());
+    headers
+}
+
+/// Stream a file compressed with chunked framing, `sample` being its start already read
+/// by [`sample_file`]. Only the sample and the encoder buffers are held in memory.
+pub(crate) fn write_compressed_file<W: Write>(
+    file: &mut File,
+    metadata: &Metadata,
+    sample: &[u8],
+    encoding: Encoding,
+    writer: W,
+) -> io::Result<W> {
+    write_chunked(Some(encoding), writer, |writer| {
+        let rest = (&mut *file).take(metadata.len().saturating_sub(sample.len() as u64));
+        let mut content = sample.chain(BufReader::new(rest));
+        io::copy(&mut content, writer)?;
+        // the payload is sent already, the client learns from the trailer that it mixes
+        // versions of the file or misses its end
+        let complete = content.into_inner().1.into_inner().limit() == 0;
+        Ok(if complete && is_unchanged(file, metadata) {
+            json!({})
+        } else {
+            json!({ "file-changed": true })
+        })
+    })
+}
+
+/// Whether the file still has the size and modification time it had when the response
+/// started, so that the payload is all from one version of it.
+fn is_unchanged(file: &File, metadata: &Metadata) -> bool {
+    file.metadata()
+        .is_ok_and(|current| is_same_version(&current, metadata))
+}
+
+pub(crate) fn is_same_version(current: &Metadata, metadata: &Metadata) -> bool {
+    current.len() == metadata.len() && current.modified().ok() == metadata.modified().ok()
+}
+
+pub(crate) fn file_changed_detail() -> ErrorDetail {
+    ErrorDetail::new(
+        "file-changed",
+        "the file changed while it was read, try again",
+    )
+}
 
-    let mut reader = BufReader::new(file);
-    io::copy(&mut reader, &mut writer)?;
+pub(crate) fn file_changed_error() -> io::Error {
+    io::Error::other("the file changed during the transfer")
+}
+
+fn perform_info_response(
+    stream: &mut CountingWriter<TcpStream>,
+    metadata: Metadata,
+) -> ssftp::error::Result<()> {
+    let headers = info_headers(&metadata);
+
+    let mut writer = BufWriter::new(stream);
+    ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
 
-fn perform_info_response(stream: TcpStream, metadata: Metadata) -> Result<(), Box<dyn Error>> {
-    println!("Performing {} response", "INFO".blue().bold());
+/// Add what describes a file to the headers of a GET or INFO response: its validators
+/// and its permission bits as `mode`, where there are such.
+pub(crate) fn file_headers(headers: serde_json::Value, metadata: &Metadata) -> serde_json::Value {
+    let mut headers = with_validators(headers, metadata);
+    if let Some(mode) = mode(metadata) {
+        headers["mode"] = json!(mode);
+    }
+    headers
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
 
-    let headers = if metadata.is_dir() {
+/// Headers of the response to an INFO request.
+pub(crate) fn info_headers(metadata: &Metadata) -> serde_json::Value {
+    if metadata.is_dir() {
         json!({ "type": "directory" })
     } else {
-        json!({ "type": "file", "content-length": metadata.len() })
+        let headers = json!({ "type": "file", "content-length": metadata.len() });
+        file_headers(headers, metadata)
+    }
+}
+
+/// Headers and payload of the response to a DIR request sent in one piece, compressed
+/// with `encoding` if it is worth it.
+pub(crate) fn dir_payload(
+    entries: Entries,
+    encoding: Option<Encoding>,
+) -> io::Result<(serde_json::Value, Vec<u8>)> {
+    let mut listing = vec![];
+    let summary = write_dir_entries(entries, &mut listing)?;
+    let (mut headers, payload) = encode_payload(listing, encoding)?;
+    if let serde_json::Value::Object(headers) = &mut headers {
+        headers.extend(summary);
+    }
+    Ok((headers, payload))
+}
+
+/// Write the payload of the response to a DIR request as the entries are read. Return
+/// the headers telling how the listing went, see [`Entries::summary`].
+fn write_dir_entries<W: Write + ?Sized>(
+    mut entries: Entries,
+    writer: &mut W,
+) -> io::Result<serde_json::Map<String, serde_json::Value>> {
+    let mut first = true;
+    for entry in entries.by_ref() {
+        if !first {
+            writer.write_all(b"\n")?;
+        }
+        writer.write_all(entry.line().as_bytes())?;
+        first = false;
+    }
+    Ok(entries.summary())
+}
+
+/// Stream a payload with chunked framing, compressed with `encoding` if any.
+/// `write_payload` writes the payload and returns the trailer headers.
+pub(crate) fn write_chunked<W, F>(
+    encoding: Option<Encoding>,
+    writer: W,
+    write_payload: F,
+) -> io::Result<W>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> io::Result<serde_json::Value>,
+{
+    let mut chunked = ChunkedWriter::new(writer);
+    let trailer = match encoding {
+        Some(encoding) => {
+            let mut encoder = encoding.encoder(chunked)?;
+            let trailer = write_payload(&mut encoder)?;
+            chunked = encoder.finish()?;
+            trailer
+        }
+        None => write_payload(&mut chunked)?,
     };
+    chunked.finish(&trailer)
+}
+
+/// Stream the payload of the response to a DIR request with chunked framing. The
+/// number of entries is only known at the end, it is sent in the trailer.
+pub(crate) fn write_chunked_listing<W: Write>(
+    entries: Entries,
+    encoding: Option<Encoding>,
+    writer: W,
+) -> io::Result<W> {
+    write_chunked(encoding, writer, |writer| {
+        write_dir_entries(entries, writer).map(serde_json::Value::Object)
+    })
+}
 
+fn perform_find_response(
+    stream: &mut CountingWriter<TcpStream>,
+    entries: Entries,
+    query: &FindQuery,
+    framing: Framing,
+) -> ssftp::error::Result<()> {
     let mut writer = BufWriter::new(stream);
-    writer.write(StatusCode::OK.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
-    writer.write(headers.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
+    if framing.chunked {
+        let headers = chunked_headers(framing.encoding);
+        ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+        write_chunked(framing.encoding, writer, |writer| {
+            query
+                .write_results(entries, writer)
+                .map(serde_json::Value::Object)
+        })?
+        .flush()?;
+        return Ok(());
+    }
+
+    let (headers, payload) = find_payload(entries, query, framing.encoding)?;
+    ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
     Ok(())
 }
 
-fn perform_dir_response(stream: TcpStream, read_dir: ReadDir) -> Result<(), Box<dyn Error>> {
-    println!("Performing {} response", "DIR".magenta().bold());
-    let entries: Vec<String> = read_dir
-        .filter_map(|entry| {
-            entry.ok().and_then(|entry| {
-                entry.file_type().ok().and_then(|ft| {
-                    if ft.is_dir() {
-                        entry
-                            .file_name()
-                            .into_string()
-                            .ok()
-                            .and_then(|dirname| Some(dirname + "/"))
-                    } else {
-                        entry.file_name().into_string().ok()
-                    }
-                })
-            })
-        })
-        .collect();
+/// Headers of a payload sent with chunked framing.
+pub(crate) fn chunked_headers(encoding: Option<Encoding>) -> serde_json::Value {
+    let mut headers = json!({ "transfer-encoding": "chunked" });
+    if let Some(encoding) = encoding {
+        headers["content-encoding"] = json!(encoding.as_str());
+    }
+    headers
+}
 
-    let response_message = entries.join("\n");
+fn perform_dir_response(
+    stream: &mut CountingWriter<TcpStream>,
+    entries: Entries,
+    framing: Framing,
+    page: Option<Page>,
+) -> ssftp::error::Result<()> {
+    if let Some(page) = page {
+        let (headers, payload) = page_payload(entries, &page, framing.encoding)?;
+        let mut writer = BufWriter::new(stream);
+        ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+        writer.write_all(&payload)?;
+        writer.flush()?;
+        return Ok(());
+    }
+    if framing.chunked {
+        let mut writer = BufWriter::new(stream);
+        let headers = chunked_headers(framing.encoding);
+        ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+        write_chunked_listing(entries, framing.encoding, writer)?.flush()?;
+        return Ok(());
+    }
+
+    let (headers, payload) = dir_payload(entries, framing.encoding)?;
     let mut writer = BufWriter::new(stream);
-    let content_length = response_message.len();
-    let headers = json!({
-        "content-length": content_length,
-        "count": entries.len(),
-    });
+    ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
 
-    writer.write(StatusCode::OK.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
-    writer.write(headers.to_string().as_bytes())?;
-    writer.write(&['\n' as u8])?;
-    writer.write(response_message.as_bytes())?;
+/// Compress a payload held in memory with `encoding` if it is worth it. Return the
+/// headers describing the payload, and the bytes to send.
+pub(crate) fn encode_payload(
+    content: Vec<u8>,
+    encoding: Option<Encoding>,
+) -> io::Result<(serde_json::Value, Vec<u8>)> {
+    if let Some(encoding) = encoding {
+        if let Some(compressed) = encoding.compress_if_beneficial(&content)? {
+            let headers = json!({
+                "content-length": compressed.len(),
+                "content-encoding": encoding.as_str(),
+                "original-length": content.len(),
+            });
+            return Ok((headers, compressed));
+        }
+    }
+    Ok((json!({ "content-length": content.len() }), content))
+}
+
+/// Headers of the response to a HELLO request: the protocol versions, methods,
+/// encodings, framings and DIR options supported by this server.
+pub(crate) fn hello_headers(compression: bool) -> serde_json::Value {
+    let methods: Vec<&str> = Method::ALL.iter().map(Method::as_str).collect();
+    let encodings: Vec<&str> = if compression {
+        Encoding::ALL.iter().map(Encoding::as_str).collect()
+    } else {
+        vec![]
+    };
+    json!({
+        "version": PROTOCOL_VERSION,
+        "min-version": MIN_PROTOCOL_VERSION,
+        "methods": methods,
+        "compression": encodings,
+        "chunked": true,
+        "dir-options": DIR_OPTIONS,
+    })
+}
+
+fn perform_not_modified_response(
+    stream: &mut CountingWriter<TcpStream>,
+    metadata: Metadata,
+) -> ssftp::error::Result<()> {
+    let headers = with_validators(json!({}), &metadata);
+
+    let mut writer = BufWriter::new(stream);
+    ResponseHead::new(StatusCode::NotModified, headers).write_to(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
 
-fn perform_bad_request_response(stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    println!("Performing {} response", "BAD REQUEST".red().bold());
+fn perform_hello_response(
+    stream: &mut CountingWriter<TcpStream>,
+    headers: serde_json::Value,
+) -> ssftp::error::Result<()> {
     let mut writer = BufWriter::new(stream);
-    writer.write(StatusCode::BadRequest.to_string().as_bytes())?;
-    writer.write(b"\n{}\n")?;
+    ResponseHead::new(StatusCode::OK, headers).write_to(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
 
 fn perform_error_response(
-    stream: TcpStream,
+    stream: &mut CountingWriter<TcpStream>,
     status_code: StatusCode,
-) -> Result<(), Box<dyn Error>> {
-    println!("Performing {} response", "ERROR".red().bold());
+    detail: &ErrorDetail,
+) -> ssftp::error::Result<()> {
     let mut writer = BufWriter::new(stream);
-    writer.write(status_code.to_string().as_bytes())?;
-    writer.write(b"\n{}\n")?;
+    ResponseHead::error(status_code, detail).write_to(&mut writer)?;
+    writer.flush()?;
     Ok(())
 }
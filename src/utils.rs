@@ -1,12 +1,15 @@
 use std::net::SocketAddr;
 use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Check the validity of path given in a client request.
 /// # Examples
 /// ```
+/// # use ssftp::utils::sanitize_request_path;
+/// # use std::path::Path;
 /// assert_eq!(sanitize_request_path(Path::new("/a/valid/request/path")), true);
 /// assert_eq!(sanitize_request_path(Path::new("/a/valid/request/dir/path/")), true);
-/// assert_eq!(sanitize_request_path(Path::new("/an/invalid/../path")), true);
+/// assert_eq!(sanitize_request_path(Path::new("/an/invalid/../path")), false);
 /// ```
 pub fn sanitize_request_path(path: &Path) -> bool {
     path.has_root() && path.components().all(|part| part != Component::ParentDir)
@@ -19,3 +22,84 @@ pub fn socket_addr_validator(s: String) -> Result<(), String> {
         Err("Invalid format of IP address".into())
     }
 }
+
+/// Broken-down UTC calendar time, used for timestamps in logs.
+#[derive(Debug, Clone, Copy)]
+pub struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millisecond: u32,
+}
+
+impl From<SystemTime> for UtcTime {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = ((secs / 86400) as i64, (secs % 86400) as u32);
+
+        // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        UtcTime {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            millisecond: since_epoch.subsec_millis(),
+        }
+    }
+}
+
+impl UtcTime {
+    /// Format as RFC 3339 with millisecond precision.
+    /// # Examples
+    /// ```
+    /// # use ssftp::utils::UtcTime;
+    /// # use std::time::{Duration, UNIX_EPOCH};
+    /// let time = UtcTime::from(UNIX_EPOCH + Duration::from_millis(1_634_567_890_123));
+    /// assert_eq!(time.to_rfc3339(), "2021-10-18T14:38:10.123Z");
+    /// ```
+    pub fn to_rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millisecond
+        )
+    }
+
+    /// Format like the timestamp of the Common Log Format, without the brackets.
+    /// # Examples
+    /// ```
+    /// # use ssftp::utils::UtcTime;
+    /// # use std::time::{Duration, UNIX_EPOCH};
+    /// let time = UtcTime::from(UNIX_EPOCH + Duration::from_secs(1_634_567_890));
+    /// assert_eq!(time.to_clf(), "18/Oct/2021:14:38:10 +0000");
+    /// ```
+    pub fn to_clf(&self) -> String {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        format!(
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}